@@ -0,0 +1,93 @@
+use crate::{core::migrations, prelude::*};
+use anyhow::Context as _;
+use std::path::{Path, PathBuf};
+use tokio::sync::{MutexGuard, OnceCell};
+use turso::{Builder, Connection, Database};
+
+register_env!(DATABASE_PATH, Option<PathBuf>);
+register_startup_listener!(open_database);
+
+pub const DEFAULT_DATABASE_PATH: &str = "./peoplebot.db";
+/// Set `DATABASE_PATH` to this for a database that only lasts as long as the process, handy for tests.
+pub const IN_MEMORY: &str = ":memory:";
+
+static DATABASE: OnceCell<Store> = OnceCell::const_new();
+
+struct Store {
+    _database: Database, //kept alive for as long as the connection is
+    connection: Mutex<Connection>,
+}
+
+/// Shared connection to the bot's database, opened and migrated on first use.
+/// It sits behind a lock as sqlite only allows a single writer, so don't hold the guard across slow work.
+pub async fn connection() -> Result<MutexGuard<'static, Connection>> {
+    let store = DATABASE.get_or_try_init(open).await?;
+    Ok(store.connection.lock().await)
+}
+
+/// Discord ids, sizes and timestamps are u64 but sqlite integers are signed, they all stay well below i64::MAX so this is lossless.
+pub const fn sql_id(id: u64) -> i64 {
+    id.cast_signed()
+}
+
+async fn open_database() -> Result<()> {
+    //opens it up front so a bad path or migration fails startup rather than the first command that needs it
+    connection().await.map(|_| ())
+}
+
+async fn open() -> Result<Store> {
+    let path = DATABASE_PATH
+        .get()
+        .clone()
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_DATABASE_PATH));
+    open_at(&path).await
+}
+
+async fn open_at(path: &Path) -> Result<Store> {
+    if path.as_os_str() != IN_MEMORY
+        && let Some(parent) = path.parent()
+    {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+
+    let database = Builder::new_local(&path.to_string_lossy())
+        .build()
+        .await
+        .with_context(|| format!("Failed to open database at {}", path.display()))?;
+    let connection = database.connect()?;
+    info!("Opened database at {}", path.display());
+    migrations::run(&connection).await?;
+
+    Ok(Store {
+        _database: database,
+        connection: Mutex::new(connection),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn registered_migrations_apply_in_memory() {
+        let store = open_at(Path::new(IN_MEMORY)).await.unwrap();
+        let connection = store.connection.lock().await;
+        let mut rows = connection
+            .query("SELECT COUNT(*) FROM schema_migrations", ())
+            .await
+            .unwrap();
+        let row = rows.next().await.unwrap().unwrap();
+        let count = inventory::iter::<crate::core::MigrationRegistry>
+            .into_iter()
+            .count();
+        let applied = row.get_value(0).unwrap();
+        assert!(
+            matches!(applied, turso::Value::Integer(applied) if applied == i64::try_from(count).unwrap()),
+            "{applied:?} of {count} applied"
+        );
+        drop(rows);
+
+        //opening again is a no-op, nothing is left pending
+        migrations::run(&connection).await.unwrap();
+    }
+}
@@ -59,6 +59,19 @@ pub async fn try_handle_error(
                 .await?;
             }
         }
+        FrameworkError::CommandCheckFailed {
+            error: Some(error),
+            ctx,
+            ..
+        } if error.is::<UserError>() => {
+            ctx.send(
+                CreateReply::default()
+                    .content(format!("{error:?}"))
+                    .reply(true)
+                    .ephemeral(true),
+            )
+            .await?;
+        }
         other => {
             poise::builtins::on_error(other).await?;
         }
@@ -0,0 +1,332 @@
+//! Schema changes modules register with `register_migration!`, applied in order when the database is opened.
+//! Each module's migrations are versioned on their own and recorded in `schema_migrations` with a checksum,
+//! so editing one that already ran refuses to boot instead of leaving databases that disagree with the code.
+use crate::{core::MigrationRegistry, prelude::*};
+use anyhow::Context as _;
+use futures::future::BoxFuture;
+use std::collections::HashMap;
+use turso::{Connection, Value};
+
+/// A single schema change, registered with `register_migration!`.
+pub struct Migration {
+    /// Versions are counted per module, name it after the tables it owns.
+    pub module: &'static str,
+    pub version: u32,
+    pub body: MigrationBody,
+    /// Of the sql or the async block's source, see [`checksum`].
+    pub checksum: u64,
+}
+
+pub enum MigrationBody {
+    /// Run as a batch, so it can hold several statements.
+    Sql(&'static str),
+    /// For changes sql can't express alone, like ones that depend on what the table looks like already.
+    Async(for<'a> fn(&'a Connection) -> BoxFuture<'a, Result<()>>),
+}
+
+/// FNV-1a of `source` with whitespace left out, so reindenting a migration doesn't count as changing it.
+pub const fn checksum(source: &str) -> u64 {
+    let bytes = source.as_bytes();
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    let mut i = 0;
+    while i < bytes.len() {
+        if !bytes[i].is_ascii_whitespace() {
+            hash ^= bytes[i] as u64;
+            hash = hash.wrapping_mul(0x0100_0000_01b3);
+        }
+        i += 1;
+    }
+    hash
+}
+
+/// Applies every registered migration that hasn't run yet, each in its own transaction.
+/// Fails when a migration that already ran was changed, or when one was added below a version that ran.
+pub async fn run(connection: &Connection) -> Result<()> {
+    let migrations = inventory::iter::<MigrationRegistry>
+        .into_iter()
+        .map(|registry| &registry.0)
+        .collect();
+    run_migrations(connection, migrations).await
+}
+
+async fn run_migrations(connection: &Connection, mut migrations: Vec<&Migration>) -> Result<()> {
+    connection
+        .execute(
+            "CREATE TABLE IF NOT EXISTS schema_migrations (
+                module TEXT NOT NULL,
+                version INTEGER NOT NULL,
+                checksum TEXT NOT NULL,
+                applied_at INTEGER NOT NULL,
+                PRIMARY KEY (module, version)
+            )",
+            (),
+        )
+        .await?;
+
+    migrations.sort_by_key(|migration| (migration.module, migration.version));
+    if let Some(pair) = migrations
+        .windows(2)
+        .find(|pair| (pair[0].module, pair[0].version) == (pair[1].module, pair[1].version))
+    {
+        bail!(
+            "Migration {} v{} is registered twice",
+            pair[0].module,
+            pair[0].version
+        );
+    }
+
+    let applied = applied(connection).await?;
+    let mut pending = Vec::new();
+    for migration in migrations {
+        let (module, version) = (migration.module, migration.version);
+        match applied.get(&(module.to_string(), version)) {
+            Some(checksum) if *checksum == format_checksum(migration.checksum) => {}
+            Some(_) => bail!(
+                "Migration {module} v{version} was changed after it ran, add a new version instead of editing it"
+            ),
+            None => pending.push(migration),
+        }
+    }
+
+    for migration in &pending {
+        let (module, version) = (migration.module, migration.version);
+        let latest = applied
+            .keys()
+            .filter(|(applied_module, _)| applied_module == module)
+            .map(|(_, applied_version)| *applied_version)
+            .max();
+        if let Some(latest) = latest
+            && latest > version
+        {
+            bail!("Migration {module} v{version} is older than v{latest} which already ran");
+        }
+    }
+
+    for migration in pending {
+        apply(connection, migration).await.with_context(|| {
+            format!(
+                "Failed to apply migration {} v{}",
+                migration.module, migration.version
+            )
+        })?;
+        info!(
+            "Applied migration {} v{}",
+            migration.module, migration.version
+        );
+    }
+    Ok(())
+}
+
+/// What's already ran, by module and version.
+async fn applied(connection: &Connection) -> Result<HashMap<(String, u32), String>> {
+    let mut rows = connection
+        .query(
+            "SELECT module, version, checksum FROM schema_migrations",
+            (),
+        )
+        .await?;
+
+    let mut applied = HashMap::new();
+    while let Some(row) = rows.next().await? {
+        match (row.get_value(0)?, row.get_value(1)?, row.get_value(2)?) {
+            (Value::Text(module), Value::Integer(version), Value::Text(checksum)) => {
+                applied.insert((module, u32::try_from(version)?), checksum);
+            }
+            other => bail!("unexpected schema_migrations row {other:?}"),
+        }
+    }
+    Ok(applied)
+}
+
+async fn apply(connection: &Connection, migration: &Migration) -> Result<()> {
+    connection.execute("BEGIN", ()).await?;
+    let result: Result<()> = async {
+        match migration.body {
+            MigrationBody::Sql(sql) => connection.execute_batch(sql).await?,
+            MigrationBody::Async(body) => body(connection).await?,
+        }
+        connection
+            .execute(
+                "INSERT INTO schema_migrations (module, version, checksum, applied_at)
+                VALUES (?1, ?2, ?3, ?4)",
+                (
+                    migration.module,
+                    i64::from(migration.version),
+                    format_checksum(migration.checksum),
+                    unix_now().cast_signed(),
+                ),
+            )
+            .await?;
+        connection.execute("COMMIT", ()).await?;
+        Ok(())
+    }
+    .await;
+
+    if result.is_err() {
+        //the migration's error is the one worth reporting, a failed rollback just means nothing was left open
+        let _ = connection.execute("ROLLBACK", ()).await;
+    }
+    result
+}
+
+/// Stored as hex as sqlite integers are signed.
+fn format_checksum(checksum: u64) -> String {
+    format!("{checksum:016x}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::database::IN_MEMORY;
+    use turso::Builder;
+
+    async fn connect() -> Connection {
+        let database = Builder::new_local(IN_MEMORY).build().await.unwrap();
+        database.connect().unwrap()
+    }
+
+    fn sql(module: &'static str, version: u32, sql: &'static str) -> Migration {
+        Migration {
+            module,
+            version,
+            body: MigrationBody::Sql(sql),
+            checksum: checksum(sql),
+        }
+    }
+
+    async fn table_exists(connection: &Connection, table: &str) -> bool {
+        let mut rows = connection
+            .query(
+                "SELECT name FROM sqlite_master WHERE type = 'table' AND name = ?1",
+                [table],
+            )
+            .await
+            .unwrap();
+        rows.next().await.unwrap().is_some()
+    }
+
+    async fn recorded(connection: &Connection) -> Vec<(String, u32)> {
+        let mut recorded = applied(connection)
+            .await
+            .unwrap()
+            .into_keys()
+            .collect::<Vec<_>>();
+        recorded.sort();
+        recorded
+    }
+
+    #[tokio::test]
+    async fn applies_in_order_across_modules() {
+        let connection = connect().await;
+        let migrations = [
+            sql("beta", 1, "INSERT INTO log (entry) VALUES ('beta 1')"),
+            sql("alpha", 2, "INSERT INTO log (entry) VALUES ('alpha 2')"),
+            sql(
+                "alpha",
+                1,
+                "CREATE TABLE log (entry TEXT NOT NULL); INSERT INTO log (entry) VALUES ('alpha 1')",
+            ),
+        ];
+        run_migrations(&connection, migrations.iter().collect())
+            .await
+            .unwrap();
+
+        let mut rows = connection
+            .query("SELECT entry FROM log ORDER BY rowid", ())
+            .await
+            .unwrap();
+        let mut log = Vec::new();
+        while let Some(row) = rows.next().await.unwrap() {
+            if let Value::Text(entry) = row.get_value(0).unwrap() {
+                log.push(entry);
+            }
+        }
+        assert_eq!(log, ["alpha 1", "alpha 2", "beta 1"]);
+        assert_eq!(
+            recorded(&connection).await,
+            [
+                ("alpha".to_string(), 1),
+                ("alpha".to_string(), 2),
+                ("beta".to_string(), 1)
+            ]
+        );
+
+        //nothing runs twice
+        run_migrations(&connection, migrations.iter().collect())
+            .await
+            .unwrap();
+        assert_eq!(recorded(&connection).await.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn refuses_changed_migrations() {
+        let connection = connect().await;
+        let original = sql("alpha", 1, "CREATE TABLE a (x INTEGER)");
+        run_migrations(&connection, vec![&original]).await.unwrap();
+
+        let reindented = sql("alpha", 1, "CREATE TABLE a (\n    x INTEGER\n)");
+        run_migrations(&connection, vec![&reindented])
+            .await
+            .unwrap();
+
+        let changed = sql("alpha", 1, "CREATE TABLE a (y INTEGER)");
+        let err = run_migrations(&connection, vec![&changed])
+            .await
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("was changed after it ran"),
+            "{err}"
+        );
+    }
+
+    #[tokio::test]
+    async fn refuses_versions_below_applied_ones() {
+        let connection = connect().await;
+        let first = sql("alpha", 1, "CREATE TABLE a (x INTEGER)");
+        let third = sql("alpha", 3, "CREATE TABLE c (x INTEGER)");
+        run_migrations(&connection, vec![&first, &third])
+            .await
+            .unwrap();
+
+        let second = sql("alpha", 2, "CREATE TABLE b (x INTEGER)");
+        let err = run_migrations(&connection, vec![&first, &second, &third])
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("older than v3"), "{err}");
+        assert!(!table_exists(&connection, "b").await);
+    }
+
+    #[tokio::test]
+    async fn refuses_duplicate_versions() {
+        let connection = connect().await;
+        let first = sql("alpha", 1, "CREATE TABLE a (x INTEGER)");
+        let duplicate = sql("alpha", 1, "CREATE TABLE b (x INTEGER)");
+        let err = run_migrations(&connection, vec![&first, &duplicate])
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("registered twice"), "{err}");
+        assert!(!table_exists(&connection, "a").await);
+    }
+
+    #[tokio::test]
+    async fn failed_migrations_roll_back() {
+        let connection = connect().await;
+        let first = sql("alpha", 1, "CREATE TABLE a (x INTEGER)");
+        let broken = sql(
+            "alpha",
+            2,
+            "CREATE TABLE partial (x INTEGER); INSERT INTO missing (x) VALUES (1)",
+        );
+        let err = run_migrations(&connection, vec![&first, &broken])
+            .await
+            .unwrap_err();
+        assert!(
+            format!("{err:#}").contains("Failed to apply migration alpha v2"),
+            "{err:#}"
+        );
+
+        assert!(table_exists(&connection, "a").await);
+        assert!(!table_exists(&connection, "partial").await);
+        assert_eq!(recorded(&connection).await, [("alpha".to_string(), 1)]);
+    }
+}
@@ -1,9 +1,15 @@
+pub mod database;
 pub mod env;
 pub mod error;
+pub mod migrations;
+pub mod prefix;
+pub mod settings;
+pub mod shutdown;
 
 pub use env::{EnvError, EnvStore, EnvValidationError};
 use futures::future::BoxFuture;
 use songbird::Songbird;
+use tokio::sync::RwLock;
 
 use crate::prelude::*;
 
@@ -30,6 +36,18 @@ inventory::collect!(CommandRegistry);
 pub struct StartupListenerRegistry(pub fn() -> BoxFuture<'static, Result<()>>);
 inventory::collect!(StartupListenerRegistry);
 
+pub struct ShutdownListenerRegistry(pub fn(Arc<RwLock<TypeMap>>) -> BoxFuture<'static, Result<()>>);
+inventory::collect!(ShutdownListenerRegistry);
+
+pub struct MigrationRegistry(pub migrations::Migration);
+inventory::collect!(MigrationRegistry);
+
+pub struct GuildSettingRegistry(pub &'static dyn settings::SettingDefinition);
+inventory::collect!(GuildSettingRegistry);
+
+pub struct UserPreferenceRegistry(pub &'static dyn settings::SettingDefinition);
+inventory::collect!(UserPreferenceRegistry);
+
 pub struct EnvRegistry(pub fn() -> BoxFuture<'static, std::result::Result<(), EnvError>>);
 inventory::collect!(EnvRegistry);
 
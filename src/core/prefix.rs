@@ -0,0 +1,39 @@
+//! Prefix commands, which answer to the guild's `prefix` setting or to a mention of the bot.
+use crate::prelude::*;
+use futures::future::BoxFuture;
+use poise::PartialContext;
+use std::time::Duration;
+
+register_env!(DEFAULT_PREFIX, Option<String>);
+
+register_guild_setting!(
+    PREFIX,
+    String,
+    "prefix",
+    default_prefix(),
+    "What messages start with to run a command, mentioning the bot always works too"
+);
+
+pub const FALLBACK_PREFIX: &str = "!";
+/// How long after sending a prefix command editing it still re-runs it.
+pub const EDIT_TRACKING_SPAN: Duration = Duration::from_secs(60 * 60);
+
+fn default_prefix() -> String {
+    DEFAULT_PREFIX
+        .get()
+        .clone()
+        .unwrap_or_else(|| FALLBACK_PREFIX.to_string())
+}
+
+/// The guild's prefix, or the default in dms.
+pub fn dynamic_prefix(
+    ctx: PartialContext<'_, GlobalState, Error>,
+) -> BoxFuture<'_, Result<Option<String>>> {
+    Box::pin(async move {
+        let prefix = match ctx.guild_id {
+            Some(guild_id) => PREFIX.get(guild_id).await?,
+            None => default_prefix(),
+        };
+        Ok(Some(prefix))
+    })
+}
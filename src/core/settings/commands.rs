@@ -0,0 +1,356 @@
+use crate::{
+    core::settings::{self, Scope, SettingDefinition},
+    prelude::*,
+};
+
+register_commands!(settings, prefs);
+
+const MAX_CHOICES: usize = 25; //discords limit on autocomplete choices
+
+/// Looks up the setting named in a command, keys come from autocomplete but can be typed freely.
+fn find_setting(scope: Scope, key: &str) -> Result<&'static dyn SettingDefinition> {
+    match (settings::definition(scope, key.trim()), scope) {
+        (Some(definition), _) => Ok(definition),
+        (None, Scope::Guild) => {
+            bail_to_user!("There's no setting called `{key}`, see `/settings list`")
+        }
+        (None, Scope::User) => {
+            bail_to_user!("There's no preference called `{key}`, see `/prefs list`")
+        }
+    }
+}
+
+async fn autocomplete_setting(_ctx: Context<'_>, partial: &str) -> CreateAutocompleteResponse {
+    autocomplete_key(Scope::Guild, partial)
+}
+
+async fn autocomplete_preference(_ctx: Context<'_>, partial: &str) -> CreateAutocompleteResponse {
+    autocomplete_key(Scope::User, partial)
+}
+
+async fn autocomplete_setting_value(ctx: Context<'_>, partial: &str) -> CreateAutocompleteResponse {
+    autocomplete_value(ctx, Scope::Guild, partial)
+}
+
+async fn autocomplete_preference_value(
+    ctx: Context<'_>,
+    partial: &str,
+) -> CreateAutocompleteResponse {
+    autocomplete_value(ctx, Scope::User, partial)
+}
+
+fn autocomplete_key(scope: Scope, partial: &str) -> CreateAutocompleteResponse {
+    let partial = partial.trim().to_lowercase();
+    let choices = settings::definitions(scope)
+        .into_iter()
+        .filter(|definition| definition.key().contains(&partial))
+        .take(MAX_CHOICES)
+        .map(|definition| {
+            //labels are capped at 100 characters
+            let label = format!("{} - {}", definition.key(), definition.description())
+                .chars()
+                .take(100)
+                .collect::<String>();
+            AutocompleteChoice::new(label, definition.key())
+        })
+        .collect();
+
+    CreateAutocompleteResponse::new().set_choices(choices)
+}
+
+/// Suggests values for the setting picked earlier in the same command.
+fn autocomplete_value(ctx: Context<'_>, scope: Scope, partial: &str) -> CreateAutocompleteResponse {
+    let partial = partial.trim().to_lowercase();
+    let choices = picked_setting(ctx, scope)
+        .map(|definition| definition.suggestions())
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|value| value.to_lowercase().starts_with(&partial))
+        .take(MAX_CHOICES)
+        .map(|value| AutocompleteChoice::new(value.clone(), value))
+        .collect();
+
+    CreateAutocompleteResponse::new().set_choices(choices)
+}
+
+/// Autocomplete only hands us the option being typed, the others have to be read off the interaction.
+fn picked_setting(ctx: Context<'_>, scope: Scope) -> Option<&'static dyn SettingDefinition> {
+    let poise::Context::Application(ctx) = ctx else {
+        return None;
+    };
+    let name = match scope {
+        Scope::Guild => "setting",
+        Scope::User => "preference",
+    };
+    let options = ctx.interaction.data.options();
+    let key = options.iter().find_map(|option| match &option.value {
+        ResolvedValue::SubCommand(options) => {
+            options.iter().find_map(|option| match option.value {
+                ResolvedValue::String(key) if option.name == name => Some(key),
+                _ => None,
+            })
+        }
+        _ => None,
+    })?;
+    settings::definition(scope, key)
+}
+
+/// Shows or changes this server's settings.
+#[command(
+    slash_command,
+    prefix_command,
+    guild_only,
+    required_permissions = "MANAGE_GUILD",
+    subcommand_required,
+    subcommands("settings_get", "settings_set", "settings_reset", "settings_list")
+)]
+pub async fn settings(_ctx: Context<'_>) -> Result<()> {
+    Ok(()) //only the subcommands can be invoked
+}
+
+#[command(
+    slash_command,
+    prefix_command,
+    track_edits,
+    guild_only,
+    required_permissions = "MANAGE_GUILD",
+    rename = "get"
+)]
+pub async fn settings_get(
+    ctx: Context<'_>,
+    #[description = "Setting to show"]
+    #[autocomplete = "autocomplete_setting"]
+    setting: String,
+) -> Result<()> {
+    let definition = find_setting(Scope::Guild, &setting)?;
+    let guild_id = ctx.guild_id().expect("guild_only command");
+    let values = settings::values(Scope::Guild, guild_id.get()).await?;
+
+    ctx.send(
+        CreateReply::new()
+            .content(describe(definition, values.get(definition.key())))
+            .ephemeral(true),
+    )
+    .await?;
+    Ok(())
+}
+
+#[command(
+    slash_command,
+    prefix_command,
+    guild_only,
+    required_permissions = "MANAGE_GUILD",
+    rename = "set"
+)]
+pub async fn settings_set(
+    ctx: Context<'_>,
+    #[description = "Setting to change"]
+    #[autocomplete = "autocomplete_setting"]
+    setting: String,
+    #[description = "New value for it"]
+    #[autocomplete = "autocomplete_setting_value"]
+    value: String,
+) -> Result<()> {
+    let definition = find_setting(Scope::Guild, &setting)?;
+    let key = definition.key();
+    let value = match definition.normalize(&value) {
+        Ok(value) => value,
+        Err(reason) => bail_to_user!("`{value}` isn't a valid value for `{key}`, {reason}"),
+    };
+    let guild_id = ctx.guild_id().expect("guild_only command");
+    settings::set(Scope::Guild, guild_id.get(), key, value.clone()).await?;
+
+    ctx.send(
+        CreateReply::new()
+            .content(format!("`{key}` is now `{value}`"))
+            .ephemeral(true),
+    )
+    .await?;
+    Ok(())
+}
+
+#[command(
+    slash_command,
+    prefix_command,
+    guild_only,
+    required_permissions = "MANAGE_GUILD",
+    rename = "reset"
+)]
+pub async fn settings_reset(
+    ctx: Context<'_>,
+    #[description = "Setting to put back to its default"]
+    #[autocomplete = "autocomplete_setting"]
+    setting: String,
+) -> Result<()> {
+    let definition = find_setting(Scope::Guild, &setting)?;
+    let guild_id = ctx.guild_id().expect("guild_only command");
+    settings::reset(Scope::Guild, guild_id.get(), definition.key()).await?;
+
+    ctx.send(
+        CreateReply::new()
+            .content(format!(
+                "`{}` is back to its default of `{}`",
+                definition.key(),
+                definition.default_setting()
+            ))
+            .ephemeral(true),
+    )
+    .await?;
+    Ok(())
+}
+
+#[command(
+    slash_command,
+    prefix_command,
+    track_edits,
+    guild_only,
+    required_permissions = "MANAGE_GUILD",
+    rename = "list"
+)]
+pub async fn settings_list(ctx: Context<'_>) -> Result<()> {
+    let guild_id = ctx.guild_id().expect("guild_only command");
+    let values = settings::values(Scope::Guild, guild_id.get()).await?;
+
+    let list = settings::definitions(Scope::Guild)
+        .into_iter()
+        .map(|definition| {
+            format!(
+                "{}\n-# {}, takes {}",
+                describe(definition, values.get(definition.key())),
+                definition.description(),
+                definition.kind()
+            )
+        })
+        .collect::<Vec<_>>();
+    let content = match list.as_slice() {
+        [] => "There aren't any settings yet".to_string(),
+        _ => list.join("\n"),
+    };
+    ctx.send(CreateReply::new().content(content).ephemeral(true))
+        .await?;
+    Ok(())
+}
+
+/// `key` and its value, marking when it's the default.
+fn describe(definition: &dyn SettingDefinition, value: Option<&String>) -> String {
+    match value {
+        Some(value) => format!("`{}` is `{value}`", definition.key()),
+        None => format!(
+            "`{}` is `{}` (default)",
+            definition.key(),
+            definition.default_setting()
+        ),
+    }
+}
+
+/// Shows or changes your own defaults for command options.
+#[command(
+    slash_command,
+    prefix_command,
+    subcommand_required,
+    subcommands("prefs_get", "prefs_set", "prefs_reset", "prefs_list")
+)]
+pub async fn prefs(_ctx: Context<'_>) -> Result<()> {
+    Ok(()) //only the subcommands can be invoked
+}
+
+#[command(slash_command, prefix_command, track_edits, rename = "get")]
+pub async fn prefs_get(
+    ctx: Context<'_>,
+    #[description = "Preference to show"]
+    #[autocomplete = "autocomplete_preference"]
+    preference: String,
+) -> Result<()> {
+    let definition = find_setting(Scope::User, &preference)?;
+    let values = settings::values(Scope::User, ctx.author().id.get()).await?;
+    let content = describe_preference(ctx, definition, values.get(definition.key())).await?;
+
+    ctx.send(CreateReply::new().content(content).ephemeral(true))
+        .await?;
+    Ok(())
+}
+
+#[command(slash_command, prefix_command, rename = "set")]
+pub async fn prefs_set(
+    ctx: Context<'_>,
+    #[description = "Preference to change"]
+    #[autocomplete = "autocomplete_preference"]
+    preference: String,
+    #[description = "New value for it"]
+    #[autocomplete = "autocomplete_preference_value"]
+    value: String,
+) -> Result<()> {
+    let definition = find_setting(Scope::User, &preference)?;
+    let key = definition.key();
+    let value = match definition.normalize(&value) {
+        Ok(value) => value,
+        Err(reason) => bail_to_user!("`{value}` isn't a valid value for `{key}`, {reason}"),
+    };
+    settings::set(Scope::User, ctx.author().id.get(), key, value.clone()).await?;
+
+    ctx.send(
+        CreateReply::new()
+            .content(format!("`{key}` is now `{value}` for you"))
+            .ephemeral(true),
+    )
+    .await?;
+    Ok(())
+}
+
+#[command(slash_command, prefix_command, rename = "reset")]
+pub async fn prefs_reset(
+    ctx: Context<'_>,
+    #[description = "Preference to go back to the default for"]
+    #[autocomplete = "autocomplete_preference"]
+    preference: String,
+) -> Result<()> {
+    let definition = find_setting(Scope::User, &preference)?;
+    settings::reset(Scope::User, ctx.author().id.get(), definition.key()).await?;
+    let content = describe_preference(ctx, definition, None).await?;
+
+    ctx.send(CreateReply::new().content(content).ephemeral(true))
+        .await?;
+    Ok(())
+}
+
+#[command(slash_command, prefix_command, track_edits, rename = "list")]
+pub async fn prefs_list(ctx: Context<'_>) -> Result<()> {
+    let values = settings::values(Scope::User, ctx.author().id.get()).await?;
+
+    let mut list = Vec::new();
+    for definition in settings::definitions(Scope::User) {
+        list.push(format!(
+            "{}\n-# {}, takes {}",
+            describe_preference(ctx, definition, values.get(definition.key())).await?,
+            definition.description(),
+            definition.kind()
+        ));
+    }
+    let content = match list.as_slice() {
+        [] => "There aren't any preferences yet".to_string(),
+        _ => list.join("\n"),
+    };
+    ctx.send(CreateReply::new().content(content).ephemeral(true))
+        .await?;
+    Ok(())
+}
+
+/// Like [`describe`], but an unset preference shows what this server's setting makes it.
+async fn describe_preference(
+    ctx: Context<'_>,
+    definition: &dyn SettingDefinition,
+    value: Option<&String>,
+) -> Result<String> {
+    let key = definition.key();
+    if value.is_some() {
+        return Ok(describe(definition, value));
+    }
+    let (Some(setting), Some(guild_id)) = (definition.guild_setting(), ctx.guild_id()) else {
+        return Ok(describe(definition, None));
+    };
+    let guild_values = settings::values(Scope::Guild, guild_id.get()).await?;
+    Ok(match guild_values.get(setting.key()) {
+        Some(value) => format!("`{key}` is `{value}` (server default)"),
+        None => describe(definition, None),
+    })
+}
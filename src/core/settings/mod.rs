@@ -0,0 +1,418 @@
+//! Per-guild settings and per-user preferences any module can define, with `register_guild_setting!` and
+//! `register_user_preference!`. Server managers change settings through `/settings`, everyone has `/prefs`.
+//! Values are stored as text in the database and cached per guild or user, anything unset falls back to its default.
+use crate::{
+    core::{
+        GuildSettingRegistry, UserPreferenceRegistry,
+        database::{self, sql_id},
+    },
+    prelude::*,
+};
+use std::{
+    collections::HashMap,
+    sync::{
+        LazyLock,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+use tokio::sync::RwLock;
+use turso::Value;
+
+mod commands;
+
+//tables from before migrations existed are already there, hence the IF NOT EXISTS
+register_migration!(
+    "settings",
+    1,
+    "CREATE TABLE IF NOT EXISTS guild_settings (
+        guild_id INTEGER NOT NULL,
+        key TEXT NOT NULL,
+        value TEXT NOT NULL,
+        PRIMARY KEY (guild_id, key)
+    )"
+);
+register_migration!(
+    "settings",
+    2,
+    "CREATE TABLE IF NOT EXISTS user_preferences (
+        user_id INTEGER NOT NULL,
+        key TEXT NOT NULL,
+        value TEXT NOT NULL,
+        PRIMARY KEY (user_id, key)
+    )"
+);
+
+/// What each guild or user has set, loaded the first time their values are read.
+static CACHE: LazyLock<RwLock<HashMap<(Scope, u64), Arc<HashMap<String, String>>>>> =
+    LazyLock::new(RwLock::default);
+/// Bumped by every write, so a load that overlapped one knows not to cache what it read.
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+const MAX_CACHED: usize = 10_000;
+
+/// Whether values belong to a guild or to a user.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Scope {
+    Guild,
+    User,
+}
+
+impl Scope {
+    const fn table(self) -> &'static str {
+        match self {
+            Self::Guild => "guild_settings",
+            Self::User => "user_preferences",
+        }
+    }
+
+    const fn id_column(self) -> &'static str {
+        match self {
+            Self::Guild => "guild_id",
+            Self::User => "user_id",
+        }
+    }
+}
+
+/// A type a setting or preference can hold, kept in the database as the text [`Self::to_setting`] gives.
+pub trait SettingValue: Sized + Send + Sync + 'static {
+    /// Tells people what to type, shown by `/settings list` and `/prefs list`.
+    const KIND: &'static str;
+
+    /// Parses what someone typed or what was stored, the error is shown to the user as is.
+    fn parse_setting(input: &str) -> Result<Self, String>;
+
+    fn to_setting(&self) -> String;
+
+    /// Values offered by autocomplete, empty for settings that take anything.
+    fn suggestions() -> Vec<String> {
+        Vec::new()
+    }
+}
+
+impl SettingValue for bool {
+    const KIND: &'static str = "true or false";
+
+    fn parse_setting(input: &str) -> Result<Self, String> {
+        match input.trim().to_lowercase().as_str() {
+            "true" | "on" | "yes" | "enabled" | "1" => Ok(true),
+            "false" | "off" | "no" | "disabled" | "0" => Ok(false),
+            _ => Err("expected true or false".to_string()),
+        }
+    }
+
+    fn to_setting(&self) -> String {
+        self.to_string()
+    }
+
+    fn suggestions() -> Vec<String> {
+        vec!["true".to_string(), "false".to_string()]
+    }
+}
+
+impl SettingValue for u32 {
+    const KIND: &'static str = "a whole number";
+
+    fn parse_setting(input: &str) -> Result<Self, String> {
+        input
+            .trim()
+            .parse()
+            .map_err(|_| "expected a whole number".to_string())
+    }
+
+    fn to_setting(&self) -> String {
+        self.to_string()
+    }
+}
+
+impl SettingValue for String {
+    const KIND: &'static str = "text";
+
+    fn parse_setting(input: &str) -> Result<Self, String> {
+        match input.trim() {
+            "" => Err("can't be empty, reset it instead".to_string()),
+            trimmed => Ok(trimmed.to_string()),
+        }
+    }
+
+    fn to_setting(&self) -> String {
+        self.clone()
+    }
+}
+
+/// The type erased side of a [`GuildSetting`] or [`UserPreference`], which is all the commands need.
+pub trait SettingDefinition: Sync {
+    fn key(&self) -> &'static str;
+    fn description(&self) -> &'static str;
+    fn kind(&self) -> &'static str;
+    /// Checks what someone typed, returning it the way it's stored.
+    fn normalize(&self, input: &str) -> Result<String, String>;
+    fn default_setting(&self) -> String;
+    fn suggestions(&self) -> Vec<String>;
+    /// The guild setting an unset preference falls back to, if it has one.
+    fn guild_setting(&self) -> Option<&'static dyn SettingDefinition> {
+        None
+    }
+}
+
+/// A setting defined with `register_guild_setting!`, read with [`Self::get`].
+pub struct GuildSetting<T> {
+    key: &'static str,
+    description: &'static str,
+    default: fn() -> T,
+}
+
+impl<T> GuildSetting<T> {
+    pub const fn new(key: &'static str, description: &'static str, default: fn() -> T) -> Self {
+        Self {
+            key,
+            description,
+            default,
+        }
+    }
+}
+
+impl<T: SettingValue> GuildSetting<T> {
+    /// The guild's value, or the default when it hasn't set one.
+    pub async fn get(&self, guild_id: GuildId) -> Result<T> {
+        let value = read(Scope::Guild, guild_id.get(), self.key).await?;
+        Ok(value.unwrap_or_else(self.default))
+    }
+}
+
+impl<T: SettingValue> SettingDefinition for GuildSetting<T> {
+    fn key(&self) -> &'static str {
+        self.key
+    }
+
+    fn description(&self) -> &'static str {
+        self.description
+    }
+
+    fn kind(&self) -> &'static str {
+        T::KIND
+    }
+
+    fn normalize(&self, input: &str) -> Result<String, String> {
+        T::parse_setting(input).map(|value| value.to_setting())
+    }
+
+    fn default_setting(&self) -> String {
+        (self.default)().to_setting()
+    }
+
+    fn suggestions(&self) -> Vec<String> {
+        T::suggestions()
+    }
+}
+
+/// What a user preference falls back to when the user hasn't set it.
+pub enum PreferenceDefault<T: 'static> {
+    Value(fn() -> T),
+    /// The guild's setting, or that setting's default outside of guilds.
+    Guild(&'static GuildSetting<T>),
+}
+
+/// A preference defined with `register_user_preference!`, read with [`Self::resolve`].
+pub struct UserPreference<T: 'static> {
+    key: &'static str,
+    description: &'static str,
+    default: PreferenceDefault<T>,
+}
+
+impl<T> UserPreference<T> {
+    pub const fn new(
+        key: &'static str,
+        description: &'static str,
+        default: PreferenceDefault<T>,
+    ) -> Self {
+        Self {
+            key,
+            description,
+            default,
+        }
+    }
+}
+
+impl<T: SettingValue> UserPreference<T> {
+    /// `explicit` when a command was given it, otherwise the user's preference, then the guild's setting,
+    /// then the built in default.
+    pub async fn resolve(
+        &self,
+        explicit: Option<T>,
+        user_id: UserId,
+        guild_id: Option<GuildId>,
+    ) -> Result<T> {
+        if let Some(value) = explicit {
+            return Ok(value);
+        }
+        if let Some(value) = read(Scope::User, user_id.get(), self.key).await? {
+            return Ok(value);
+        }
+        match (&self.default, guild_id) {
+            (PreferenceDefault::Guild(setting), Some(guild_id)) => setting.get(guild_id).await,
+            (PreferenceDefault::Guild(setting), None) => Ok((setting.default)()),
+            (PreferenceDefault::Value(default), _) => Ok(default()),
+        }
+    }
+}
+
+impl<T: SettingValue> SettingDefinition for UserPreference<T> {
+    fn key(&self) -> &'static str {
+        self.key
+    }
+
+    fn description(&self) -> &'static str {
+        self.description
+    }
+
+    fn kind(&self) -> &'static str {
+        T::KIND
+    }
+
+    fn normalize(&self, input: &str) -> Result<String, String> {
+        T::parse_setting(input).map(|value| value.to_setting())
+    }
+
+    fn default_setting(&self) -> String {
+        match &self.default {
+            PreferenceDefault::Value(default) => default().to_setting(),
+            PreferenceDefault::Guild(setting) => setting.default_setting(),
+        }
+    }
+
+    fn suggestions(&self) -> Vec<String> {
+        T::suggestions()
+    }
+
+    fn guild_setting(&self) -> Option<&'static dyn SettingDefinition> {
+        match &self.default {
+            PreferenceDefault::Value(_) => None,
+            PreferenceDefault::Guild(setting) => Some(*setting),
+        }
+    }
+}
+
+/// Every registered setting or preference, sorted by key.
+pub fn definitions(scope: Scope) -> Vec<&'static dyn SettingDefinition> {
+    let mut definitions = match scope {
+        Scope::Guild => inventory::iter::<GuildSettingRegistry>
+            .into_iter()
+            .map(|registry| registry.0)
+            .collect::<Vec<_>>(),
+        Scope::User => inventory::iter::<UserPreferenceRegistry>
+            .into_iter()
+            .map(|registry| registry.0)
+            .collect::<Vec<_>>(),
+    };
+    definitions.sort_by_key(|definition| definition.key());
+    definitions
+}
+
+pub fn definition(scope: Scope, key: &str) -> Option<&'static dyn SettingDefinition> {
+    definitions(scope)
+        .into_iter()
+        .find(|definition| definition.key() == key)
+}
+
+/// The parsed value stored under `key`, `None` when it isn't set.
+async fn read<T: SettingValue>(scope: Scope, id: u64, key: &str) -> Result<Option<T>> {
+    let values = values(scope, id).await?;
+    let Some(value) = values.get(key) else {
+        return Ok(None);
+    };
+    //a stored value can go stale when a setting's type changes, the default is the best we can do
+    Ok(T::parse_setting(value)
+        .inspect_err(|err| warn!("Ignoring stored {key} for {scope:?} {id}: {err}"))
+        .ok())
+}
+
+/// Everything the guild or user has set, in the stored form.
+pub async fn values(scope: Scope, id: u64) -> Result<Arc<HashMap<String, String>>> {
+    if let Some(values) = CACHE.read().await.get(&(scope, id)) {
+        return Ok(values.clone());
+    }
+
+    //loaded without the lock so a slow load doesn't hold up everyone else's reads,
+    //a write made meanwhile bumps the generation and the stale result isn't kept
+    let generation = GENERATION.load(Ordering::Acquire);
+    let values = Arc::new(load(scope, id).await?);
+    //empty results are cached too, most users never set a preference and would otherwise hit the database every time
+    let mut cache = CACHE.write().await;
+    if GENERATION.load(Ordering::Acquire) == generation {
+        if cache.len() >= MAX_CACHED
+            && let Some(evicted) = cache.keys().next().copied()
+        {
+            cache.remove(&evicted); //whichever comes first, it's only reloaded if it's used again
+        }
+        cache.insert((scope, id), values.clone());
+    }
+    Ok(values)
+}
+
+async fn load(scope: Scope, id: u64) -> Result<HashMap<String, String>> {
+    let connection = database::connection().await?;
+    let mut rows = connection
+        .query(
+            &format!(
+                "SELECT key, value FROM {} WHERE {} = ?1",
+                scope.table(),
+                scope.id_column()
+            ),
+            [sql_id(id)],
+        )
+        .await?;
+
+    let mut values = HashMap::new();
+    while let Some(row) = rows.next().await? {
+        match (row.get_value(0)?, row.get_value(1)?) {
+            (Value::Text(key), Value::Text(value)) => {
+                values.insert(key, value);
+            }
+            other => bail!("unexpected {} row {other:?}", scope.table()),
+        }
+    }
+    Ok(values)
+}
+
+/// Stores an already normalized value, for callers that only have the key like `/settings`.
+pub async fn set(scope: Scope, id: u64, key: &str, value: String) -> Result<()> {
+    write(scope, id, key, Some(value)).await
+}
+
+pub async fn reset(scope: Scope, id: u64, key: &str) -> Result<()> {
+    write(scope, id, key, None).await
+}
+
+/// Stores `value` under `key`, `None` removes it so the default applies again.
+async fn write(scope: Scope, id: u64, key: &str, value: Option<String>) -> Result<()> {
+    let (table, id_column) = (scope.table(), scope.id_column());
+    let connection = database::connection().await?;
+    match &value {
+        Some(value) => {
+            connection
+                .execute(
+                    &format!(
+                        "INSERT INTO {table} ({id_column}, key, value) VALUES (?1, ?2, ?3)
+                        ON CONFLICT ({id_column}, key) DO UPDATE SET value = excluded.value"
+                    ),
+                    (sql_id(id), key, value.as_str()),
+                )
+                .await?;
+        }
+        None => {
+            connection
+                .execute(
+                    &format!("DELETE FROM {table} WHERE {id_column} = ?1 AND key = ?2"),
+                    (sql_id(id), key),
+                )
+                .await?;
+        }
+    }
+    drop(connection);
+
+    //dropped rather than updated so writes racing each other can't leave the older value cached,
+    //the next read loads whichever landed last
+    let mut cache = CACHE.write().await;
+    GENERATION.fetch_add(1, Ordering::Release);
+    cache.remove(&(scope, id));
+    Ok(())
+}
@@ -0,0 +1,63 @@
+//! Stops the bot cleanly on SIGTERM or Ctrl+C, which is how docker and most hosts ask it to stop.
+//! New commands are refused from then on, while the hooks registered with `register_shutdown_listener!`
+//! get to wrap up whatever their module has in flight.
+use crate::{core::Context, prelude::*};
+use futures::future::BoxFuture;
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+register_env!(SHUTDOWN_GRACE_SECS, Option<u64>);
+
+pub const DEFAULT_SHUTDOWN_GRACE_SECS: u64 = 8; //docker kills the container 10 seconds after asking it to stop
+
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+
+/// Whether the bot has been asked to stop, anything that starts new work should check this first.
+pub fn is_shutting_down() -> bool {
+    SHUTTING_DOWN.load(Ordering::Relaxed)
+}
+
+/// How long stopping may take in total, shutdown hooks should be done by the end of it.
+pub fn grace_period() -> Duration {
+    Duration::from_secs(
+        SHUTDOWN_GRACE_SECS
+            .get()
+            .unwrap_or(DEFAULT_SHUTDOWN_GRACE_SECS),
+    )
+}
+
+/// Waits for a stop signal, then marks the bot as shutting down.
+pub async fn wait_for_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = terminate.recv() => {},
+                    _ = tokio::signal::ctrl_c() => {},
+                }
+            }
+            Err(err) => {
+                warn!("Failed to listen for SIGTERM, only Ctrl+C will shut down cleanly: {err}");
+                tokio::signal::ctrl_c().await.ok();
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await.ok();
+
+    SHUTTING_DOWN.store(true, Ordering::Relaxed);
+}
+
+/// Command check that turns away new commands once shutdown has started.
+pub fn refuse_while_shutting_down(_ctx: Context<'_>) -> BoxFuture<'_, Result<bool>> {
+    Box::pin(async move {
+        if is_shutting_down() {
+            bail_to_user!("The bot is restarting, try again in a minute");
+        }
+        Ok(true)
+    })
+}
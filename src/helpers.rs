@@ -2,13 +2,13 @@ use crate::prelude::*;
 
 /// Returns the maximum attachment size limit for a guild.
 pub fn attachment_byte_limit(ctx: &Context, guild_id: Option<GuildId>) -> u64 {
+    guild_byte_limit(&ctx.serenity_context().cache, guild_id)
+}
+
+/// Same as [`attachment_byte_limit`], for code that only has the cache, like event listeners.
+pub fn guild_byte_limit(cache: &Cache, guild_id: Option<GuildId>) -> u64 {
     let tier = guild_id
-        .and_then(|id| {
-            ctx.serenity_context()
-                .cache
-                .guild(id)
-                .map(|guild| guild.premium_tier)
-        })
+        .and_then(|id| cache.guild(id).map(|guild| guild.premium_tier))
         .unwrap_or(PremiumTier::Tier0);
 
     match tier {
@@ -72,6 +72,91 @@ pub fn format_bytes(bytes: u64) -> String {
     "0 B".to_string()
 }
 
+/// Formats a duration into a short human-readable string, e.g. `1h 5m`, `3m 20s` or `45s`.
+pub fn format_duration(duration: std::time::Duration) -> String {
+    let total = duration.as_secs();
+    let (hours, minutes, seconds) = (total / 3600, (total % 3600) / 60, total % 60);
+
+    if hours > 0 {
+        format!("{hours}h {minutes}m") //seconds are noise at this point
+    } else if minutes > 0 {
+        format!("{minutes}m {seconds}s")
+    } else {
+        format!("{seconds}s")
+    }
+}
+
+/// Formats a count the way sites show view counts, e.g. `950`, `12.3K` or `4.1M`.
+pub fn format_count(count: u64) -> String {
+    const UNITS: [(&str, u64); 3] = [("B", 1_000_000_000), ("M", 1_000_000), ("K", 1_000)];
+
+    for &(unit, factor) in UNITS.iter() {
+        if count >= factor {
+            //one decimal, dropped when it's zero so 2.0K reads as 2K
+            let tenths = (count.saturating_mul(10) + factor / 2) / factor;
+            return match tenths % 10 {
+                0 => format!("{}{unit}", tenths / 10),
+                decimal => format!("{}.{decimal}{unit}", tenths / 10),
+            };
+        }
+    }
+    count.to_string()
+}
+
+/// Escapes markdown and breaks up mentions, for text from outside discord like video titles.
+pub fn escape_markdown(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\\' | '*' | '_' | '~' | '`' | '|' | '>' | '#' | '[' | ']' | '(' | ')' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            '@' => escaped.push_str("@\u{200B}"), //zero width space, so @everyone in a title cant ping
+            '\n' | '\r' => escaped.push(' '),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Parses a timestamp written as seconds (`90`, `12.5`), `mm:ss` or `hh:mm:ss`.
+pub fn parse_timestamp(input: &str) -> Option<std::time::Duration> {
+    let parts = input.trim().split(':').collect::<Vec<_>>();
+    if parts.is_empty() || parts.len() > 3 {
+        return None;
+    }
+
+    let (seconds, rest) = parts.split_last()?;
+    let seconds = seconds
+        .parse::<f64>()
+        .ok()
+        .filter(|s| s.is_finite() && *s >= 0.0)?;
+    if !rest.is_empty() && seconds >= 60.0 {
+        return None; //3:75 is almost certainly a typo
+    }
+
+    let mut total = 0u64;
+    for (index, part) in rest.iter().enumerate() {
+        let value = part.parse::<u64>().ok()?;
+        //minutes are capped when hours are given, hours and lone minutes arent
+        if index == 1 && value >= 60 {
+            return None;
+        }
+        total = total.checked_mul(60)?.checked_add(value)?;
+    }
+
+    std::time::Duration::try_from_secs_f64(total as f64 * 60.0 + seconds).ok()
+}
+
+/// Seconds since the unix epoch, what timestamps are stored as.
+pub fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or(0)
+}
+
 /// Edit an existing message or send a new one if the handle has expired
 /// Will only return an error if a new message cannot be sent
 pub async fn edit_or_send_new<'a>(
@@ -99,3 +184,48 @@ pub async fn edit_or_send_new<'a>(
         Ok(handle)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn parses_timestamps() {
+        let cases = [
+            ("90", Some(Duration::from_secs(90))),
+            (" 7 ", Some(Duration::from_secs(7))),
+            ("12.5", Some(Duration::from_millis(12_500))),
+            ("1:30", Some(Duration::from_secs(90))),
+            ("1:05.25", Some(Duration::from_millis(65_250))),
+            ("90:00", Some(Duration::from_secs(90 * 60))),
+            ("1:02:03", Some(Duration::from_secs(3723))),
+            ("26:00:00", Some(Duration::from_secs(26 * 3600))),
+            ("0", Some(Duration::ZERO)),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(parse_timestamp(input), expected, "{input}");
+        }
+    }
+
+    #[test]
+    fn rejects_bad_timestamps() {
+        for input in [
+            "",
+            "abc",
+            "1:2:3:4",
+            "3:75",
+            "1:60:00",
+            "-5",
+            "1:-5",
+            "NaN",
+            "inf",
+            "1::2",
+            "1.5:30",
+            "1e400",
+            "999999999999999999:00:00",
+        ] {
+            assert_eq!(parse_timestamp(input), None, "{input}");
+        }
+    }
+}
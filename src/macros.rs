@@ -108,6 +108,43 @@ macro_rules! register_startup_listener {
     };
 }
 
+/// Registers an async shutdown hook to be called once the bot is asked to stop, before the shards disconnect.
+/// Hooks are handed the global data so they can wrap up whatever their module has running.
+/// ```
+/// use peoplebot::prelude::*;
+/// use tokio::sync::RwLock;
+///
+/// async fn shutdown_listener(data: Arc<RwLock<TypeMap>>) -> Result<()> {
+///     Ok(())
+/// }
+///
+/// register_shutdown_listener!(shutdown_listener);
+/// ```
+/// This macro is just short hand for the following:
+/// ```
+/// inventory::submit! {
+///    peoplebot::core::ShutdownListenerRegistry(shutdown_listener)
+///}
+/// ```
+#[macro_export]
+macro_rules! register_shutdown_listener {
+    ($handler:path) => {
+        const _: () = {
+            fn __peoplebot_shutdown_wrapper(
+                data: ::std::sync::Arc<
+                    ::tokio::sync::RwLock<::poise::serenity_prelude::prelude::TypeMap>,
+                >,
+            ) -> ::futures::future::BoxFuture<'static, $crate::prelude::Result<()>> {
+                async move { $handler(data).await }.boxed()
+            }
+
+            ::inventory::submit! {
+                $crate::core::ShutdownListenerRegistry(__peoplebot_shutdown_wrapper)
+            }
+        };
+    };
+}
+
 /// Registers a global data initializer function to be invoked during framework startup.
 /// The registered initializer must insert the data into the [`TypeMap`].
 /// This macro can be invoked multiple times if you prefer separate types instead of nesting them.
@@ -275,6 +312,149 @@ macro_rules! register_env {
     };
 }
 
+/// Registers a database migration, applied once when the database is first opened.
+/// Versions count up from 1 for each module, and a migration can't be edited once it has run anywhere,
+/// add a new version instead. The body is either sql, which may hold several statements, or an async block
+/// given the connection. Each runs in a transaction along with recording it.
+/// ```
+/// use peoplebot::prelude::*;
+///
+/// register_migration!(
+///     "reminders",
+///     1,
+///     "CREATE TABLE reminders (id TEXT PRIMARY KEY, due_at INTEGER NOT NULL)"
+/// );
+/// register_migration!("reminders", 2, async |connection| {
+///     connection
+///         .execute("ALTER TABLE reminders ADD COLUMN note TEXT", ())
+///         .await?;
+///     Ok(())
+/// });
+/// ```
+#[macro_export]
+macro_rules! register_migration {
+    ($module:literal, $version:literal, async |$connection:ident| $body:block $(,)?) => {
+        const _: () = {
+            fn __peoplebot_migration_wrapper<'a>(
+                connection: &'a ::turso::Connection,
+            ) -> ::futures::future::BoxFuture<'a, $crate::prelude::Result<()>> {
+                ::std::boxed::Box::pin(async move {
+                    let $connection = connection;
+                    $body
+                })
+            }
+
+            ::inventory::submit! {
+                $crate::core::MigrationRegistry($crate::core::migrations::Migration {
+                    module: $module,
+                    version: $version,
+                    body: $crate::core::migrations::MigrationBody::Async(
+                        __peoplebot_migration_wrapper,
+                    ),
+                    checksum: $crate::core::migrations::checksum(stringify!($body)),
+                })
+            }
+        };
+    };
+
+    ($module:literal, $version:literal, $sql:expr $(,)?) => {
+        const _: () = {
+            ::inventory::submit! {
+                $crate::core::MigrationRegistry($crate::core::migrations::Migration {
+                    module: $module,
+                    version: $version,
+                    body: $crate::core::migrations::MigrationBody::Sql($sql),
+                    checksum: $crate::core::migrations::checksum($sql),
+                })
+            }
+        };
+    };
+}
+
+/// Registers a per-guild setting, which server managers can change with `/settings`.
+/// The type must implement [`crate::core::settings::SettingValue`], the default applies until a guild sets its own value.
+/// Keys are shown to users, keep them short and prefix them with your module.
+/// ```
+/// use peoplebot::prelude::*;
+///
+/// register_guild_setting!(
+///     WELCOME_MESSAGES,
+///     bool,
+///     "welcome_messages",
+///     true,
+///     "Greet new members in the system channel"
+/// );
+///
+/// async fn example(guild_id: GuildId) -> Result<()> {
+///     if WELCOME_MESSAGES.get(guild_id).await? {
+///         //...
+///     }
+///     Ok(())
+/// }
+/// ```
+#[macro_export]
+macro_rules! register_guild_setting {
+    ($store:ident, $ty:ty, $key:literal, $default:expr, $description:literal $(,)?) => {
+        pub static $store: $crate::core::settings::GuildSetting<$ty> =
+            $crate::core::settings::GuildSetting::new($key, $description, || $default);
+
+        const _: () = {
+            ::inventory::submit! {
+                $crate::core::GuildSettingRegistry(&$store)
+            }
+        };
+    };
+}
+
+/// Registers a per-user preference, which users can change for themselves with `/prefs`.
+/// Commands read it with `resolve`, which prefers what was passed to the command and falls back to the preference,
+/// then to the default. The default is either a value, or `guild = SETTING` to use a guild setting of the same type.
+/// ```
+/// use peoplebot::prelude::*;
+///
+/// register_guild_setting!(LOUD, bool, "loud", false, "Shout replies by default");
+/// register_user_preference!(LOUD_PREF, bool, "loud", guild = LOUD, "Always shout replies");
+///
+/// #[command(slash_command)]
+/// async fn reply(ctx: Context<'_>, loud: Option<bool>) -> Result<()> {
+///     let loud = LOUD_PREF.resolve(loud, ctx.author().id, ctx.guild_id()).await?;
+///     Ok(())
+/// }
+/// ```
+#[macro_export]
+macro_rules! register_user_preference {
+    (@store $store:ident, $ty:ty, $key:literal, $default:expr, $description:literal) => {
+        pub static $store: $crate::core::settings::UserPreference<$ty> =
+            $crate::core::settings::UserPreference::new($key, $description, $default);
+
+        const _: () = {
+            ::inventory::submit! {
+                $crate::core::UserPreferenceRegistry(&$store)
+            }
+        };
+    };
+
+    ($store:ident, $ty:ty, $key:literal, guild = $setting:path, $description:literal $(,)?) => {
+        $crate::register_user_preference!(@store
+            $store,
+            $ty,
+            $key,
+            $crate::core::settings::PreferenceDefault::Guild(&$setting),
+            $description
+        );
+    };
+
+    ($store:ident, $ty:ty, $key:literal, $default:expr, $description:literal $(,)?) => {
+        $crate::register_user_preference!(@store
+            $store,
+            $ty,
+            $key,
+            $crate::core::settings::PreferenceDefault::Value(|| $default),
+            $description
+        );
+    };
+}
+
 /// Returns early from the current function with a [`crate::core::error::UserError`] that is safe to show to end users.
 /// Don't forget to delete any temporary ephemerals before calling this.
 #[macro_export]
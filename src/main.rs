@@ -1,13 +1,14 @@
 #![warn(clippy::pedantic, clippy::cargo, clippy::nursery)]
 
 use crate::{
-    core::{GlobalDataRegistry, error::handle_error},
+    core::{GlobalDataRegistry, error::handle_error, prefix, shutdown},
     prelude::*,
 };
-use core::{EnvRegistry, EnvValidationError, StartupListenerRegistry};
+use core::{EnvRegistry, EnvValidationError, ShutdownListenerRegistry, StartupListenerRegistry};
 use dotenvy::dotenv;
 use futures::future::{join_all, try_join_all};
-use poise::{Framework, FrameworkOptions};
+use poise::{EditTracker, Framework, FrameworkOptions, PrefixFrameworkOptions};
+use tokio::sync::RwLock;
 use tracing_subscriber::{EnvFilter, filter::LevelFilter, fmt};
 
 mod core;
@@ -29,14 +30,28 @@ async fn main() -> Result<()> {
     verify_env_requirements().await?;
     fire_startup_events().await?;
 
-    let intents = GatewayIntents::GUILDS | GatewayIntents::GUILD_MESSAGES;
+    let intents = GatewayIntents::GUILDS
+        | GatewayIntents::GUILD_MESSAGES
+        | GatewayIntents::DIRECT_MESSAGES //so prefix commands work in dms
+        | GatewayIntents::MESSAGE_CONTENT; //privileged, needed for prefix commands and to find links for auto embeds
     let framework = init_framework();
     let token = DISCORD_TOKEN.get();
 
     let mut client = ClientBuilder::new(token, intents)
         .framework(framework)
         .await?;
+
+    let shard_manager = client.shard_manager.clone();
+    let data = client.data.clone();
+    tokio::spawn(async move {
+        shutdown::wait_for_signal().await;
+        fire_shutdown_events(data).await;
+        //ends client.start below once every shard has disconnected
+        shard_manager.shutdown_all().await;
+    });
+
     client.start().await?;
+    info!("Shut down cleanly");
     Ok(())
 }
 
@@ -44,8 +59,17 @@ fn init_framework() -> Framework<GlobalState, Error> {
     Framework::builder()
         .options(FrameworkOptions {
             commands: collect_commands(),
+            prefix_options: PrefixFrameworkOptions {
+                dynamic_prefix: Some(prefix::dynamic_prefix),
+                mention_as_prefix: true,
+                edit_tracker: Some(Arc::new(EditTracker::for_timespan(
+                    prefix::EDIT_TRACKING_SPAN,
+                ))),
+                ..Default::default()
+            },
             event_handler: |framework, event| Box::pin(event_handler(framework, event)),
             on_error: |error| Box::pin(handle_error(error)),
+            command_check: Some(shutdown::refuse_while_shutting_down),
             ..Default::default()
         })
         .setup(move |ctx, _ready, framework| {
@@ -84,6 +108,21 @@ async fn fire_startup_events() -> Result<()> {
     Ok(())
 }
 
+async fn fire_shutdown_events(data: Arc<RwLock<TypeMap>>) {
+    let futures = inventory::iter::<ShutdownListenerRegistry>
+        .into_iter()
+        .map(|listener| listener.0(data.clone()))
+        .collect::<Vec<_>>();
+    info!("Shutting down, firing {} shutdown events", futures.len());
+
+    //unlike startups every hook runs to the end, one module failing to clean up shouldnt stop the others
+    for result in join_all(futures).await {
+        if let Err(err) = result {
+            error!("Shutdown event failed: {err:#}");
+        }
+    }
+}
+
 async fn verify_env_requirements() -> Result<()> {
     let futures = inventory::iter::<EnvRegistry>
         .into_iter()
@@ -0,0 +1,436 @@
+//! Remembers where previously embedded media can be found, so reposts of the same clip are posted
+//! straight away instead of being downloaded and encoded again.
+//!
+//! Entries are keyed on the extractor, the media's id and the encode options. The link a request came
+//! from is mapped to its entry as well, so most repeats are found before yt-dlp even runs.
+//! The cache is best effort, failures are logged and treated as a miss.
+use crate::{
+    core::database::{self, sql_id},
+    modules::embedder::{
+        model::*,
+        queue::normalize_url,
+        storage::{EmbedStorage, Uploaded},
+        url_policy::UrlPolicy,
+    },
+    prelude::*,
+};
+use anyhow::Context as _;
+use std::time::Duration;
+use turso::{Row, Value};
+
+register_env!(EMBEDDER_CACHE_TTL_HOURS, Option<u64>);
+register_env!(EMBEDDER_CACHE_MAX_BYTES, Option<u64>);
+
+register_migration!(
+    "embedder_cache",
+    1,
+    "CREATE TABLE IF NOT EXISTS embedder_cache (
+        media_key TEXT PRIMARY KEY,
+        kind TEXT NOT NULL,
+        url TEXT NOT NULL,
+        object_key TEXT,
+        size INTEGER NOT NULL,
+        expires_at INTEGER NOT NULL,
+        last_used INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS embedder_cache_sources (
+        source_key TEXT PRIMARY KEY,
+        media_key TEXT NOT NULL
+    );"
+);
+//kept apart from the entries so caches from before captions existed carry on working
+register_migration!(
+    "embedder_cache",
+    2,
+    "CREATE TABLE IF NOT EXISTS embedder_cache_info (
+        media_key TEXT PRIMARY KEY,
+        info TEXT NOT NULL
+    )"
+);
+//hits are checked against the requester's host rules, the page a link resolved to is what they apply to
+register_migration!(
+    "embedder_cache",
+    3,
+    "ALTER TABLE embedder_cache ADD COLUMN webpage_url TEXT"
+);
+
+pub const DEFAULT_CACHE_TTL_HOURS: u64 = 72;
+pub const DEFAULT_CACHE_MAX_BYTES: u64 = 5 * 1024 * 1024 * 1024;
+const ATTACHMENT_LIFETIME: Duration = Duration::from_secs(20 * 60 * 60); //discord signs cdn links for 24 hours
+const STORAGE_MARGIN: Duration = Duration::from_secs(60 * 60); //the storage sweeper runs hourly
+
+/// Where a cached file is kept.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum CacheKind {
+    /// Uploaded to embed storage, counts towards `EMBEDDER_CACHE_MAX_BYTES`.
+    Storage,
+    /// Attached to a message we sent, discord hosts it for us.
+    Attachment,
+}
+
+impl CacheKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Storage => "storage",
+            Self::Attachment => "attachment",
+        }
+    }
+}
+
+impl FromStr for CacheKind {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "storage" => Ok(Self::Storage),
+            "attachment" => Ok(Self::Attachment),
+            other => bail!("unknown cache kind {other}"),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct CachedEmbed {
+    pub url: String,
+    /// For the caption, missing for entries cached before captions existed.
+    pub info: Option<MediaInfo>,
+    /// Page the media was found on after redirects, missing for entries cached before it was recorded.
+    webpage_url: Option<String>,
+    kind: CacheKind,
+    object_key: Option<String>,
+    size: u64,
+    expires_at: u64,
+}
+
+impl CachedEmbed {
+    pub fn uploaded(
+        uploaded: &Uploaded,
+        size: u64,
+        storage: &EmbedStorage,
+        info: Option<MediaInfo>,
+    ) -> Self {
+        Self {
+            url: uploaded.url.to_string(),
+            webpage_url: info.as_ref().and_then(|info| info.webpage_url.clone()),
+            info,
+            kind: CacheKind::Storage,
+            object_key: Some(uploaded.key.clone()),
+            size,
+            expires_at: expires_at(storage.link_lifetime().saturating_sub(STORAGE_MARGIN)),
+        }
+    }
+
+    pub fn attachment(url: String, size: u64, info: Option<MediaInfo>) -> Self {
+        Self {
+            url,
+            webpage_url: info.as_ref().and_then(|info| info.webpage_url.clone()),
+            info,
+            kind: CacheKind::Attachment,
+            object_key: None,
+            size,
+            expires_at: expires_at(ATTACHMENT_LIFETIME),
+        }
+    }
+
+    fn from_row(row: &Row) -> Result<Self> {
+        let text = |index: usize| -> Result<Option<String>> {
+            match row.get_value(index)? {
+                Value::Text(text) => Ok(Some(text)),
+                Value::Null => Ok(None),
+                other => bail!("unexpected cache column {other:?}"),
+            }
+        };
+        let integer = |index: usize| -> Result<u64> {
+            match row.get_value(index)? {
+                Value::Integer(value) => Ok(u64::try_from(value).unwrap_or(0)),
+                other => bail!("unexpected cache column {other:?}"),
+            }
+        };
+
+        Ok(Self {
+            url: text(0)?.context("cache entry without a url")?,
+            //a caption is nice to have, an unreadable one shouldnt cost us the entry
+            info: text(6)?.and_then(|info| serde_json::from_str(&info).ok()),
+            webpage_url: text(7)?,
+            kind: text(1)?.context("cache entry without a kind")?.parse()?,
+            object_key: text(2)?,
+            size: integer(3)?,
+            expires_at: integer(4)?,
+        })
+    }
+}
+
+/// The keys a job's result is cached under, the media key is only known once yt-dlp has identified it.
+pub struct CacheLookup {
+    source_key: String,
+    options: String,
+    media_key: Option<String>,
+}
+
+impl CacheLookup {
+    pub fn new(
+        url: &Url,
+        mode: OutputMode,
+        format: VideoFormat,
+        clip: Option<ClipRange>,
+        byte_limit: u64,
+    ) -> Self {
+        let clip = clip.map(ClipRange::download_section).unwrap_or_default();
+        let mut options = format!("{}:{clip}:{byte_limit}", mode.as_str());
+        //the default format keeps the keys entries had before the format could be picked
+        if format != VideoFormat::default() {
+            options += &format!(":{}", format.as_key());
+        }
+
+        Self {
+            source_key: format!("{}|{options}", normalize_url(url)),
+            options,
+            media_key: None,
+        }
+    }
+
+    /// Sets the media key from what yt-dlp reported, the same media reached through any link shares it.
+    pub fn identify(&mut self, extractor: &str, id: &str) {
+        self.media_key = Some(format!(
+            "{}:{id}:{}",
+            extractor.to_lowercase(),
+            self.options
+        ));
+    }
+
+    /// Entry for the link the request came from, when `policy` allows where it led.
+    pub async fn find_source(&self, policy: &UrlPolicy) -> Option<CachedEmbed> {
+        let found = find(
+            "SELECT c.url, c.kind, c.object_key, c.size, c.expires_at, c.media_key, i.info,
+                c.webpage_url
+            FROM embedder_cache_sources s JOIN embedder_cache c ON c.media_key = s.media_key
+            LEFT JOIN embedder_cache_info i ON i.media_key = c.media_key
+            WHERE s.source_key = ?1 AND c.expires_at > ?2",
+            &self.source_key,
+        )
+        .await
+        .inspect_err(|err| warn!("Failed to read embed cache: {err:#}"))
+        .ok()
+        .flatten()?;
+        allowed(found, policy).await
+    }
+
+    /// Entry for the identified media when `policy` allows where it was found, the link is remembered for next time
+    /// when there is one.
+    pub async fn find_media(&self, policy: &UrlPolicy) -> Option<CachedEmbed> {
+        let media_key = self.media_key.as_deref()?;
+        let found = find(
+            "SELECT c.url, c.kind, c.object_key, c.size, c.expires_at, c.media_key, i.info,
+                c.webpage_url
+            FROM embedder_cache c LEFT JOIN embedder_cache_info i ON i.media_key = c.media_key
+            WHERE c.media_key = ?1 AND c.expires_at > ?2",
+            media_key,
+        )
+        .await
+        .inspect_err(|err| warn!("Failed to read embed cache: {err:#}"))
+        .ok()
+        .flatten()?;
+        let found = allowed(found, policy).await?;
+
+        if let Err(err) = self.link_source(media_key).await {
+            warn!("Failed to write embed cache: {err:#}");
+        }
+        Some(found)
+    }
+
+    /// Records where the result of this job was posted, `storage` is needed to evict uploads once the cache is over its size limit.
+    pub async fn store(&self, entry: CachedEmbed, storage: Option<&EmbedStorage>) {
+        let Some(media_key) = self.media_key.as_deref() else {
+            return; //yt-dlp never told us what it downloaded
+        };
+        if let Err(err) = self.insert(media_key, &entry).await {
+            warn!("Failed to write embed cache: {err:#}");
+            return;
+        }
+        if let Err(err) = purge_expired().await {
+            warn!("Failed to purge embed cache: {err:#}");
+        }
+        //only uploads count towards the size limit, attachments are hosted by discord
+        if let Some(storage) = storage
+            && let Err(err) = evict_uploads(storage).await
+        {
+            warn!("Failed to evict from embed cache: {err:#}");
+        }
+    }
+
+    async fn insert(&self, media_key: &str, entry: &CachedEmbed) -> Result<()> {
+        let connection = database::connection().await?;
+        //a replaced upload is left for the storage sweeper, its link may still be in use
+        connection
+            .execute(
+                "INSERT INTO embedder_cache
+                    (media_key, kind, url, object_key, size, expires_at, last_used, webpage_url)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)
+                ON CONFLICT (media_key) DO UPDATE SET kind = excluded.kind, url = excluded.url,
+                    object_key = excluded.object_key, size = excluded.size,
+                    expires_at = excluded.expires_at, last_used = excluded.last_used,
+                    webpage_url = excluded.webpage_url",
+                (
+                    media_key,
+                    entry.kind.as_str(),
+                    entry.url.as_str(),
+                    entry.object_key.as_deref(),
+                    sql_id(entry.size),
+                    sql_id(entry.expires_at),
+                    sql_id(unix_now()),
+                    entry.webpage_url.as_deref(),
+                ),
+            )
+            .await?;
+        if let Some(info) = &entry.info {
+            connection
+                .execute(
+                    "INSERT INTO embedder_cache_info (media_key, info) VALUES (?1, ?2)
+                    ON CONFLICT (media_key) DO UPDATE SET info = excluded.info",
+                    (media_key, serde_json::to_string(info)?),
+                )
+                .await?;
+        }
+        drop(connection);
+        self.link_source(media_key).await
+    }
+
+    async fn link_source(&self, media_key: &str) -> Result<()> {
+        let connection = database::connection().await?;
+        connection
+            .execute(
+                "INSERT INTO embedder_cache_sources (source_key, media_key) VALUES (?1, ?2)
+                ON CONFLICT (source_key) DO UPDATE SET media_key = excluded.media_key",
+                (self.source_key.as_str(), media_key),
+            )
+            .await?;
+        Ok(())
+    }
+}
+
+/// Runs a lookup that selects an entry's columns followed by its media key, info and page, marking the entry as used
+/// on a hit.
+async fn find(sql: &str, key: &str) -> Result<Option<CachedEmbed>> {
+    let connection = database::connection().await?;
+    let now = sql_id(unix_now());
+    let mut rows = connection.query(sql, (key, now)).await?;
+    let Some(row) = rows.next().await? else {
+        return Ok(None);
+    };
+
+    let entry = CachedEmbed::from_row(&row)?;
+    let Value::Text(media_key) = row.get_value(5)? else {
+        bail!("cache entry without a media key");
+    };
+    drop(rows);
+
+    connection
+        .execute(
+            "UPDATE embedder_cache SET last_used = ?1 WHERE media_key = ?2",
+            (now, media_key),
+        )
+        .await?;
+    Ok(Some(entry))
+}
+
+/// Passes on a hit only when `policy` allows the page it was found on, it may have been cached for a guild with other
+/// host rules. Entries from before the page was recorded can't be checked, so they count as a miss.
+async fn allowed(entry: CachedEmbed, policy: &UrlPolicy) -> Option<CachedEmbed> {
+    let webpage_url = Url::parse(entry.webpage_url.as_deref()?).ok()?;
+    match policy.check_host(&webpage_url).await {
+        Ok(()) => Some(entry),
+        Err(err) => {
+            debug!("Not reposting the cached embed of {webpage_url}: {err}");
+            None
+        }
+    }
+}
+
+/// Drops expired entries along with any links and info that pointed at them.
+async fn purge_expired() -> Result<()> {
+    let connection = database::connection().await?;
+    //expired uploads are past their retention, the storage sweeper deletes those objects
+    connection
+        .execute(
+            "DELETE FROM embedder_cache WHERE expires_at <= ?1",
+            [sql_id(unix_now())],
+        )
+        .await?;
+    connection
+        .execute(
+            "DELETE FROM embedder_cache_sources
+            WHERE media_key NOT IN (SELECT media_key FROM embedder_cache)",
+            (),
+        )
+        .await?;
+    connection
+        .execute(
+            "DELETE FROM embedder_cache_info
+            WHERE media_key NOT IN (SELECT media_key FROM embedder_cache)",
+            (),
+        )
+        .await?;
+    Ok(())
+}
+
+/// Deletes the least recently used uploads until they fit under `EMBEDDER_CACHE_MAX_BYTES`.
+async fn evict_uploads(storage: &EmbedStorage) -> Result<()> {
+    let limit = EMBEDDER_CACHE_MAX_BYTES
+        .get()
+        .unwrap_or(DEFAULT_CACHE_MAX_BYTES);
+
+    let mut over_limit = Vec::new();
+    {
+        let connection = database::connection().await?;
+        let mut rows = connection
+            .query(
+                "SELECT media_key, object_key, size FROM embedder_cache
+                WHERE kind = ?1 ORDER BY last_used DESC",
+                [CacheKind::Storage.as_str()],
+            )
+            .await?;
+
+        let mut total = 0u64;
+        while let Some(row) = rows.next().await? {
+            match (row.get_value(0)?, row.get_value(1)?, row.get_value(2)?) {
+                (Value::Text(media_key), object_key, Value::Integer(size)) => {
+                    total = total.saturating_add(u64::try_from(size).unwrap_or(0));
+                    if total > limit {
+                        let object_key = match object_key {
+                            Value::Text(object_key) => Some(object_key),
+                            _ => None,
+                        };
+                        over_limit.push((media_key, object_key));
+                    }
+                }
+                other => bail!("unexpected cache row {other:?}"),
+            }
+        }
+    }
+
+    //the connection isnt held while talking to storage, which can take a while
+    for (media_key, object_key) in over_limit {
+        if let Some(object_key) = object_key
+            && let Err(err) = storage.delete(&object_key).await
+        {
+            warn!("Failed to delete cached embed {object_key}: {err:#}");
+            continue; //keep the entry so we try again next time
+        }
+        database::connection()
+            .await?
+            .execute(
+                "DELETE FROM embedder_cache WHERE media_key = ?1",
+                [media_key.as_str()],
+            )
+            .await?;
+    }
+    Ok(())
+}
+
+fn expires_at(lifetime: Duration) -> u64 {
+    let hours = EMBEDDER_CACHE_TTL_HOURS
+        .get()
+        .unwrap_or(DEFAULT_CACHE_TTL_HOURS);
+    let ttl = Duration::from_secs(hours * 60 * 60);
+    unix_now() + lifetime.min(ttl).as_secs()
+}
@@ -1,119 +1,525 @@
-use crate::{modules::embedder::model::*, prelude::*};
-use tokio::fs;
+use crate::{
+    core::error::UserError,
+    modules::embedder::{
+        config,
+        listener::extract_links,
+        model::*,
+        pipeline::{self, EmbedJob, StatusMessage},
+        url_policy::{HostRule, normalize_host},
+    },
+    prelude::*,
+};
+use futures::{StreamExt, future::join_all};
+use std::time::Duration;
 
-register_commands!(embed);
+register_commands!(embed, embed_message, auto_embed, embed_hosts);
 
+const PICK_TIMEOUT: Duration = Duration::from_secs(2 * 60);
+const MAX_PICKS: usize = 25; //discords limit on select menu options
+
+/// Offers the heights we encode to, marking the one used when none is picked.
+async fn autocomplete_quality(ctx: Context<'_>, partial: &str) -> CreateAutocompleteResponse {
+    let default = config::QUALITY_PREF
+        .resolve(None, ctx.author().id, ctx.guild_id())
+        .await
+        .map_or(DEFAULT_QUALITY, |Quality(quality)| quality);
+
+    let partial = partial.trim().to_lowercase();
+    let choices = QUALITY_HEIGHTS
+        .iter()
+        .map(|height| (*height, format!("{height}p")))
+        .filter(|(_, quality)| quality.starts_with(&partial))
+        .map(|(height, quality)| {
+            let label = if height == default {
+                format!("{quality} (default)")
+            } else {
+                quality.clone()
+            };
+            AutocompleteChoice::new(label, quality)
+        })
+        .collect();
+
+    CreateAutocompleteResponse::new().set_choices(choices)
+}
+
+/// Parses the quality option, which is free text as autocomplete only suggests values.
+fn quality_option(quality: Option<&str>) -> Result<Option<u32>> {
+    let Some(quality) = quality else {
+        return Ok(None);
+    };
+    match parse_quality(quality) {
+        Some(height) => Ok(Some(height)),
+        None => bail_to_user!(
+            "`{quality}` isn't a quality we can embed at, pick one of the suggestions like `720p`"
+        ),
+    }
+}
+
+//no track_edits, a re-run would download and post the whole thing again
 #[command(slash_command, prefix_command)]
 pub async fn embed(
     ctx: Context<'_>,
     link: String,
-    #[description = "Whether to embed the link anonymously"] anonymous: Option<bool>,
-    #[description = "Whether to strip audio from the video"] strip_audio: Option<bool>,
+    #[description = "Whether to embed the link anonymously, defaults to your preference"]
+    anonymous: Option<bool>,
+    #[description = "Whether to strip audio from the video, defaults to your preference"]
+    strip_audio: Option<bool>,
+    #[description = "Only embed the audio, in this format"] audio_only: Option<AudioFormat>,
+    #[description = "Highest resolution to embed at, defaults to your preference"]
+    #[autocomplete = "autocomplete_quality"]
+    quality: Option<String>,
+    #[description = "Container for the video, webm is smaller but has to be allowed by the server"]
+    container: Option<VideoContainer>,
+    #[description = "Where the clip starts, as seconds, mm:ss or hh:mm:ss"] start: Option<String>,
+    #[description = "Where the clip ends, as seconds, mm:ss or hh:mm:ss"] end: Option<String>,
+    #[description = "How long the clip runs for, instead of an end"] duration: Option<String>,
 ) -> Result<()> {
     ctx.defer_ephemeral().await?; //defer gives us 15m to reply before it ends the interaction
 
-    let anonymous = anonymous.unwrap_or(false);
-    let strip_audio = strip_audio.unwrap_or(false);
+    let (author, guild_id) = (ctx.author().id, ctx.guild_id());
+    let anonymous = config::ANONYMOUS_PREF
+        .resolve(anonymous, author, guild_id)
+        .await?;
+    let mode = match (audio_only, strip_audio) {
+        (Some(_), Some(true)) => bail_to_user!("Can't strip the audio from an audio only embed"),
+        (Some(format), _) => OutputMode::Audio(format),
+        //only an explicit strip conflicts with audio only, a preference for it doesn't
+        (None, strip_audio) => match config::STRIP_AUDIO_PREF
+            .resolve(strip_audio, author, guild_id)
+            .await?
+        {
+            true => OutputMode::Muted,
+            false => OutputMode::Video,
+        },
+    };
+    let clip = clip_range(start.as_deref(), end.as_deref(), duration.as_deref())?;
+    let quality = quality_option(quality.as_deref())?;
     let url = Url::parse(&link)?;
 
-    let original_url = url.clone(); //a copy we can use later
     let name = if anonymous {
         "anon".to_string()
     } else {
         ctx.author().mention().to_string()
     };
 
-    let embedder_data = {
-        let data = ctx.serenity_context().data.read().await;
-        data.get::<EmbedderDataKey>()
-            .expect("Embedder data not found")
-            .clone()
-    };
-
-    let (sender, mut receiver) = watch::channel(YtDlpEvent::Unknown);
-    let request = DownloadRequest {
+    let job = EmbedJob {
         url,
-        strip_audio,
-        sender,
+        mode,
+        quality,
+        container,
+        clip,
+        requester: ctx.author().id,
+        guild_id: ctx.guild_id(),
+        sent_by: name,
+        channel_id: ctx.channel_id(),
+        reply_to: None,
+        byte_limit: attachment_byte_limit(&ctx, ctx.guild_id()),
+        requested_at: unix_now(),
     };
+    let mut status = StatusMessage::Reply { ctx, handle: None };
+    pipeline::run(ctx.serenity_context(), job, &mut status).await?;
+    Ok(())
+}
 
-    #[allow(unused_assignments)] //it doesnt see it gets used in edit_or_send_new
-    let mut handle: Option<ReplyHandle> = None;
+/// Embeds the links in someone else's message, replying to it with the result.
+#[command(context_menu_command = "Embed this")]
+pub async fn embed_message(ctx: Context<'_>, message: Message) -> Result<()> {
+    ctx.defer_ephemeral().await?;
+
+    let links = extract_links(&message.content, false);
+    let links = match links.len() {
+        0 => bail_to_user!("That message doesn't have any links in it"),
+        1 => links,
+        _ => match pick_links(&ctx, links).await? {
+            Some(picked) => picked,
+            None => return Ok(()),
+        },
+    };
 
+    let (author, guild_id) = (ctx.author().id, ctx.guild_id());
+    let anonymous = config::ANONYMOUS_PREF
+        .resolve(None, author, guild_id)
+        .await?;
+    let mode = match config::STRIP_AUDIO_PREF
+        .resolve(None, author, guild_id)
+        .await?
     {
-        let data = embedder_data.lock().await;
-        match data.download_queue.try_enqueue(request) {
-            Ok(_) => {
-                handle = ctx.reply(format!("Awaiting Download...")).await.ok();
-            }
-            Err(_) => {
-                bail_to_user!("Failed to queue download, server might be overloaded");
+        true => OutputMode::Muted,
+        false => OutputMode::Video,
+    };
+
+    //the reply already points at whoever posted the link, anonymous only hides who asked for the embed
+    let sent_by = match (message.author.id == author, anonymous) {
+        (true, true) => "anon".to_string(),
+        (true, false) => ctx.author().mention().to_string(),
+        (false, true) => message.author.mention().to_string(),
+        (false, false) => format!(
+            "{} via {}",
+            message.author.mention(),
+            ctx.author().mention()
+        ),
+    };
+
+    let jobs = links.into_iter().map(|url| {
+        let job = EmbedJob {
+            url,
+            mode,
+            quality: None, //the pipeline falls back to the requester's quality and container, as with /embed
+            container: None,
+            clip: None,
+            requester: author,
+            guild_id,
+            sent_by: sent_by.clone(),
+            channel_id: message.channel_id,
+            reply_to: Some(MessageReference::from(&message)),
+            byte_limit: attachment_byte_limit(&ctx, guild_id),
+            requested_at: unix_now(),
+        };
+        async move {
+            let mut status = StatusMessage::Reply { ctx, handle: None };
+            pipeline::run(ctx.serenity_context(), job, &mut status).await
+        }
+    });
+
+    //each link reports on its own, so failures are sent separately instead of only surfacing the first
+    for result in join_all(jobs).await {
+        match result {
+            Ok(_) => {}
+            //formatted like the shared error handler formats user errors
+            Err(err) if err.is::<UserError>() => {
+                ctx.send(
+                    CreateReply::default()
+                        .content(format!("{err:?}"))
+                        .reply(true)
+                        .ephemeral(true),
+                )
+                .await
+                .ok();
             }
+            Err(err) => return Err(err),
         }
     }
+    Ok(())
+}
 
-    while receiver.changed().await.is_ok() {
-        let event = receiver.borrow_and_update().clone(); //can be done without a clone but its so messy, minor perf is negligible
-        match event {
-            YtDlpEvent::DLStarted { .. } => {
-                // the .. ignores any remaining fields that we dont care for
-                handle = edit_or_send_new(&ctx, handle, format!("Downloading..."))
-                    .await
-                    .ok();
-            }
-            YtDlpEvent::DLProgress { percent, .. } => {
-                handle = edit_or_send_new(&ctx, handle, format!("Downloading... {}", percent))
-                    .await
-                    .ok();
-            }
-            YtDlpEvent::PPStarted { .. } => {
-                handle = edit_or_send_new(&ctx, handle, format!("Processing..."))
-                    .await
-                    .ok();
-            }
-            YtDlpEvent::PPProgress { percent, .. } => {
-                handle = edit_or_send_new(&ctx, handle, format!("Processing... {}", percent))
-                    .await
-                    .ok();
+/// Asks which of `links` to embed, `None` if the invoker doesn't pick any in time.
+async fn pick_links(ctx: &Context<'_>, mut links: Vec<Url>) -> Result<Option<Vec<Url>>> {
+    links.truncate(MAX_PICKS);
+    let menu_id = format!("embed-pick-{}", ctx.id());
+
+    let options = links
+        .iter()
+        .enumerate()
+        .map(|(index, url)| {
+            //labels are capped at 100 characters
+            let label = url.as_str().chars().take(100).collect::<String>();
+            CreateSelectMenuOption::new(label, index.to_string())
+        })
+        .collect::<Vec<_>>();
+    let menu = CreateSelectMenu::new(
+        menu_id.clone(),
+        CreateSelectMenuKind::String {
+            options: options.into(),
+        },
+    )
+    .placeholder("Links to embed")
+    .min_values(1)
+    .max_values(u8::try_from(links.len()).unwrap_or(u8::MAX));
+
+    let handle = ctx
+        .send(
+            CreateReply::new()
+                .content("Which links should be embedded?")
+                .components(vec![CreateActionRow::SelectMenu(menu)]),
+        )
+        .await?;
+
+    let author = ctx.author().id;
+    let filter_id = menu_id.clone();
+    let press = ComponentInteractionCollector::new(ctx.serenity_context())
+        .filter(move |press| press.data.custom_id.as_str() == filter_id && press.user.id == author)
+        .timeout(PICK_TIMEOUT)
+        .stream()
+        .next()
+        .await;
+
+    let Some(press) = press else {
+        handle
+            .edit(
+                *ctx,
+                CreateReply::new()
+                    .content("No links were picked in time")
+                    .components(vec![]),
+            )
+            .await
+            .ok();
+        return Ok(None);
+    };
+
+    let picked = match &press.data.kind {
+        ComponentInteractionDataKind::StringSelect { values } => values
+            .iter()
+            .filter_map(|value| value.parse::<usize>().ok())
+            .filter_map(|index| links.get(index).cloned())
+            .collect::<Vec<_>>(),
+        _ => Vec::new(),
+    };
+
+    let response = CreateInteractionResponse::UpdateMessage(
+        CreateInteractionResponseMessage::new()
+            .content(format!(
+                "Embedding {} of {} links",
+                picked.len(),
+                links.len()
+            ))
+            .components(vec![]),
+    );
+    press.create_response(&ctx.http(), response).await.ok();
+
+    Ok(Some(picked))
+}
+
+/// Sets whether links posted in chat get embedded, for the whole server or a single channel.
+#[command(
+    slash_command,
+    prefix_command,
+    guild_only,
+    required_permissions = "MANAGE_GUILD",
+    rename = "autoembed"
+)]
+pub async fn auto_embed(
+    ctx: Context<'_>,
+    #[description = "Embed links automatically, offer a button to embed them, or ignore them"]
+    mode: AutoEmbedMode,
+    #[description = "Only apply to this channel instead of the server"] channel: Option<
+        GuildChannel,
+    >,
+) -> Result<()> {
+    let guild_id = ctx.guild_id().expect("guild_only command");
+    let channel_id = channel.as_ref().map(|channel| channel.id.get());
+    config::set_auto_embed_mode(guild_id.get(), channel_id, mode).await?;
+
+    let scope = match &channel {
+        Some(channel) => channel.mention().to_string(),
+        None => "this server".to_string(),
+    };
+    ctx.send(
+        CreateReply::new()
+            .content(format!("Auto embed set to `{}` for {scope}", mode.as_str()))
+            .ephemeral(true),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Manages the hosts this server allows or blocks embedding from, on top of the bot's own lists.
+#[command(
+    slash_command,
+    prefix_command,
+    guild_only,
+    required_permissions = "MANAGE_GUILD",
+    rename = "embedhosts",
+    subcommand_required,
+    subcommands("hosts_set", "hosts_remove", "hosts_list")
+)]
+pub async fn embed_hosts(_ctx: Context<'_>) -> Result<()> {
+    Ok(()) //only the subcommands can be invoked
+}
+
+/// Allows or blocks a host and its subdomains, once any host is allowed only those can be embedded.
+#[command(slash_command, prefix_command, guild_only, rename = "set")]
+pub async fn hosts_set(
+    ctx: Context<'_>,
+    #[description = "Host to allow or block, like example.com"] host: String,
+    #[description = "Whether to allow or block it"] rule: HostRule,
+) -> Result<()> {
+    let Some(host) = normalize_host(&host) else {
+        bail_to_user!("`{host}` isn't a valid host");
+    };
+    let guild_id = ctx.guild_id().expect("guild_only command");
+    config::set_host_rule(guild_id.get(), &host, Some(rule)).await?;
+
+    ctx.send(
+        CreateReply::new()
+            .content(format!("`{host}` is now set to {}", rule.as_str()))
+            .ephemeral(true),
+    )
+    .await?;
+    Ok(())
+}
+
+#[command(slash_command, prefix_command, guild_only, rename = "remove")]
+pub async fn hosts_remove(
+    ctx: Context<'_>,
+    #[description = "Host to remove the rule for"] host: String,
+) -> Result<()> {
+    let Some(host) = normalize_host(&host) else {
+        bail_to_user!("`{host}` isn't a valid host");
+    };
+    let guild_id = ctx.guild_id().expect("guild_only command");
+    if !config::set_host_rule(guild_id.get(), &host, None).await? {
+        bail_to_user!("`{host}` doesn't have a rule");
+    }
+
+    ctx.send(
+        CreateReply::new()
+            .content(format!("Removed the rule for `{host}`"))
+            .ephemeral(true),
+    )
+    .await?;
+    Ok(())
+}
+
+#[command(
+    slash_command,
+    prefix_command,
+    track_edits,
+    guild_only,
+    rename = "list"
+)]
+pub async fn hosts_list(ctx: Context<'_>) -> Result<()> {
+    let guild_id = ctx.guild_id().expect("guild_only command");
+    let rules = config::host_rules(guild_id.get()).await?;
+
+    let list = |hosts: &[String]| match hosts {
+        [] => "none".to_string(),
+        hosts => hosts
+            .iter()
+            .map(|host| format!("`{host}`"))
+            .collect::<Vec<_>>()
+            .join(", "),
+    };
+    ctx.send(
+        CreateReply::new()
+            .content(format!(
+                "Allowed: {}\nBlocked: {}",
+                list(&rules.allow),
+                list(&rules.deny)
+            ))
+            .ephemeral(true),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Validates the trim options up front so a bad range never reaches the queue.
+fn clip_range(
+    start: Option<&str>,
+    end: Option<&str>,
+    duration: Option<&str>,
+) -> Result<Option<ClipRange>> {
+    let parse = |input: &str| match parse_timestamp(input) {
+        Some(timestamp) => Ok(timestamp),
+        None => bail_to_user!("`{input}` isn't a valid timestamp, use seconds, mm:ss or hh:mm:ss"),
+    };
+
+    let start_at = start.map(parse).transpose()?.unwrap_or_default();
+    let end_at = match (end, duration) {
+        (Some(_), Some(_)) => bail_to_user!("Give either an end or a duration, not both"),
+        (Some(end), None) => Some(parse(end)?),
+        (None, Some(duration)) => {
+            let duration = parse(duration)?;
+            if duration.is_zero() {
+                bail_to_user!("The clip duration has to be longer than 0 seconds");
             }
-            YtDlpEvent::Finished { path, .. } => {
-                let file_size = fs::metadata(&path).await?;
-                let guild_limit = attachment_byte_limit(&ctx, ctx.guild_id());
-                if file_size.len() > guild_limit {
-                    fs::remove_file(&path).await.ok();
-                    handle.delete(ctx).await.ok();
-
-                    let reply = CreateMessage::new() //dont use <> to allow it to embed if provider supports it, as we failed to
-                        .content(format!("-# sent by: {name} - [[link]]({original_url})"));
-                    ctx.channel_id().send_message(&ctx.http(), reply).await.ok();
-
-                    bail_to_user!(
-                        "File for [[link]](<{original_url}>) too large to embed, server limit is {}, file size is {}, sent link instead",
-                        format_bytes(guild_limit),
-                        format_bytes(file_size.len())
-                    );
-                }
-                let attachment = CreateAttachment::path(&path).await?; //can fail to open the file, but not likely
-
-                let message = CreateMessage::new()
-                    .content(format!("-# sent by: {name} - [[link]](<{original_url}>)"))
-                    .add_file(attachment);
-
-                ctx.channel_id()
-                    .send_message(&ctx.http(), message)
-                    .await
-                    .ok(); //consume potential error
-                //theres nothing we can do if it fails to send, and we want to make sure to delete the file afterwards
-                handle.delete(ctx).await.ok();
-
-                if let Err(_) = fs::remove_file(&path).await {
-                    error!("Failed to remove file: {path}"); //we dont bail because the core logic still succeeded
-                }
-                break;
+            match start_at.checked_add(duration) {
+                Some(end_at) => Some(end_at),
+                None => bail_to_user!("That clip runs for longer than any video could"),
             }
-            _ => { /*discard other events*/ }
         }
+        (None, None) => None,
+    };
+
+    if let Some(end_at) = end_at
+        && end_at <= start_at
+    {
+        bail_to_user!(
+            "The clip has to end after it starts, it starts at {} and ends at {}",
+            format_duration(start_at),
+            format_duration(end_at)
+        );
     }
 
-    Ok(())
+    if start.is_none() && end_at.is_none() {
+        return Ok(None);
+    }
+    Ok(Some(ClipRange {
+        start: start_at,
+        end: end_at,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user_error(result: Result<Option<ClipRange>>) -> String {
+        let err = result.unwrap_err();
+        assert!(err.is::<UserError>(), "{err:?}");
+        err.to_string()
+    }
+
+    #[test]
+    fn builds_clip_ranges() {
+        let secs = Duration::from_secs;
+        assert_eq!(clip_range(None, None, None).unwrap(), None);
+        assert_eq!(
+            clip_range(Some("1:00"), None, None).unwrap(),
+            Some(ClipRange {
+                start: secs(60),
+                end: None
+            })
+        );
+        assert_eq!(
+            clip_range(None, Some("30"), None).unwrap(),
+            Some(ClipRange {
+                start: secs(0),
+                end: Some(secs(30))
+            })
+        );
+        assert_eq!(
+            clip_range(Some("10"), Some("1:00"), None).unwrap(),
+            Some(ClipRange {
+                start: secs(10),
+                end: Some(secs(60))
+            })
+        );
+        assert_eq!(
+            clip_range(Some("1:00"), None, Some("15")).unwrap(),
+            Some(ClipRange {
+                start: secs(60),
+                end: Some(secs(75))
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_bad_clip_ranges() {
+        assert!(
+            user_error(clip_range(Some("1:00"), Some("1:00"), None))
+                .contains("end after it starts")
+        );
+        assert!(
+            user_error(clip_range(Some("2:00"), Some("1:00"), None))
+                .contains("end after it starts")
+        );
+        assert!(user_error(clip_range(None, Some("0"), None)).contains("end after it starts"));
+        assert!(user_error(clip_range(None, Some("10"), Some("5"))).contains("not both"));
+        assert!(user_error(clip_range(None, None, Some("0"))).contains("longer than 0"));
+        assert!(
+            user_error(clip_range(Some("soon"), None, None)).contains("isn't a valid timestamp")
+        );
+        assert!(
+            user_error(clip_range(Some("1:75"), None, None)).contains("isn't a valid timestamp")
+        );
+        //just under the longest duration there is
+        assert!(
+            user_error(clip_range(
+                Some("18446744073709549568"),
+                None,
+                Some("1:00:00")
+            ))
+            .contains("longer than any video")
+        );
+    }
 }
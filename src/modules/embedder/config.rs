@@ -0,0 +1,182 @@
+use crate::{
+    core::database::{self, sql_id},
+    modules::embedder::{
+        model::*,
+        url_policy::{HostRule, HostRules},
+    },
+    prelude::*,
+};
+use turso::Value;
+
+register_migration!(
+    "embedder_config",
+    1,
+    "CREATE TABLE IF NOT EXISTS embedder_auto_embed (
+        guild_id INTEGER NOT NULL,
+        channel_id INTEGER NOT NULL,
+        mode TEXT NOT NULL,
+        PRIMARY KEY (guild_id, channel_id)
+    );
+    CREATE TABLE IF NOT EXISTS embedder_host_rules (
+        guild_id INTEGER NOT NULL,
+        host TEXT NOT NULL,
+        rule TEXT NOT NULL,
+        PRIMARY KEY (guild_id, host)
+    );"
+);
+
+register_guild_setting!(
+    EMBED_CAPTIONS,
+    bool,
+    "embed_captions",
+    true,
+    "Show the title, uploader, length and views above embeds"
+);
+register_guild_setting!(
+    EMBED_QUALITY,
+    Quality,
+    "embed_quality",
+    Quality(DEFAULT_QUALITY),
+    "Highest resolution embeds use unless the requester picks one"
+);
+//some clients only play mp4 inline, so webm is opt in
+register_guild_setting!(
+    EMBED_ALLOW_WEBM,
+    bool,
+    "embed_allow_webm",
+    false,
+    "Whether members can ask for webm embeds, which some clients can't play inline"
+);
+register_guild_setting!(
+    EMBED_ANONYMOUS,
+    bool,
+    "embed_anonymous",
+    false,
+    "Whether /embed hides who asked for it unless the requester says otherwise"
+);
+register_guild_setting!(
+    EMBED_STRIP_AUDIO,
+    bool,
+    "embed_strip_audio",
+    false,
+    "Whether /embed strips the audio unless the requester says otherwise"
+);
+
+register_user_preference!(
+    ANONYMOUS_PREF,
+    bool,
+    "embed_anonymous",
+    guild = EMBED_ANONYMOUS,
+    "Hide your name on your /embed posts"
+);
+register_user_preference!(
+    STRIP_AUDIO_PREF,
+    bool,
+    "embed_strip_audio",
+    guild = EMBED_STRIP_AUDIO,
+    "Strip the audio from videos you /embed"
+);
+register_user_preference!(
+    QUALITY_PREF,
+    Quality,
+    "embed_quality",
+    guild = EMBED_QUALITY,
+    "Highest resolution your embeds use when you don't pick one"
+);
+register_user_preference!(
+    CONTAINER_PREF,
+    VideoContainer,
+    "embed_container",
+    VideoContainer::Mp4,
+    "Container for your embeds, webm is only used where the server allows it"
+);
+
+const GUILD_WIDE: u64 = 0; //channel id stored for the server wide setting, no channel can have it
+
+/// The channel's own mode if it has one, otherwise the server's, guilds are opted out until they set one.
+pub async fn auto_embed_mode(guild_id: u64, channel_id: u64) -> Result<AutoEmbedMode> {
+    let connection = database::connection().await?;
+    let mut rows = connection
+        .query(
+            //the channel row sorts first as real ids are always above GUILD_WIDE
+            "SELECT mode FROM embedder_auto_embed
+            WHERE guild_id = ?1 AND channel_id IN (?2, ?3)
+            ORDER BY channel_id DESC LIMIT 1",
+            [sql_id(guild_id), sql_id(channel_id), sql_id(GUILD_WIDE)],
+        )
+        .await?;
+
+    match rows.next().await? {
+        Some(row) => match row.get_value(0)? {
+            Value::Text(mode) => mode.parse(),
+            other => bail!("unexpected auto embed mode {other:?}"),
+        },
+        None => Ok(AutoEmbedMode::default()),
+    }
+}
+
+/// Sets the mode for one channel, or the whole server when `channel_id` is `None`.
+pub async fn set_auto_embed_mode(
+    guild_id: u64,
+    channel_id: Option<u64>,
+    mode: AutoEmbedMode,
+) -> Result<()> {
+    let connection = database::connection().await?;
+    connection
+        .execute(
+            "INSERT INTO embedder_auto_embed (guild_id, channel_id, mode) VALUES (?1, ?2, ?3)
+            ON CONFLICT (guild_id, channel_id) DO UPDATE SET mode = excluded.mode",
+            (
+                sql_id(guild_id),
+                sql_id(channel_id.unwrap_or(GUILD_WIDE)),
+                mode.as_str(),
+            ),
+        )
+        .await?;
+    Ok(())
+}
+
+/// The guild's own allow and deny lists, checked on top of the bot wide ones.
+pub async fn host_rules(guild_id: u64) -> Result<HostRules> {
+    let connection = database::connection().await?;
+    let mut rows = connection
+        .query(
+            "SELECT host, rule FROM embedder_host_rules WHERE guild_id = ?1 ORDER BY host",
+            [sql_id(guild_id)],
+        )
+        .await?;
+
+    let mut rules = HostRules::default();
+    while let Some(row) = rows.next().await? {
+        match (row.get_value(0)?, row.get_value(1)?) {
+            (Value::Text(host), Value::Text(rule)) => rules.push(host, rule.parse()?),
+            other => bail!("unexpected host rule row {other:?}"),
+        }
+    }
+    Ok(rules)
+}
+
+/// Adds or replaces the rule for `host`, `None` removes it. Returns whether anything changed.
+pub async fn set_host_rule(guild_id: u64, host: &str, rule: Option<HostRule>) -> Result<bool> {
+    let connection = database::connection().await?;
+    let changed = match rule {
+        Some(rule) => {
+            connection
+                .execute(
+                    "INSERT INTO embedder_host_rules (guild_id, host, rule) VALUES (?1, ?2, ?3)
+                    ON CONFLICT (guild_id, host) DO UPDATE SET rule = excluded.rule",
+                    (sql_id(guild_id), host, rule.as_str()),
+                )
+                .await?
+        }
+        None => {
+            connection
+                .execute(
+                    "DELETE FROM embedder_host_rules WHERE guild_id = ?1 AND host = ?2",
+                    (sql_id(guild_id), host),
+                )
+                .await?
+        }
+    };
+    Ok(changed > 0)
+}
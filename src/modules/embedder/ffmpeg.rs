@@ -0,0 +1,1048 @@
+use crate::{modules::embedder::model::*, prelude::*};
+use std::{
+    path::{Path, PathBuf},
+    process::Stdio,
+    time::{Duration, Instant},
+};
+use tokio::{
+    fs,
+    io::{AsyncBufReadExt, AsyncReadExt, BufReader},
+};
+use tokio_util::sync::CancellationToken;
+
+const CONTAINER_OVERHEAD: f64 = 0.96; //leave ~4% of the budget for container headers and muxing
+const MAX_VIDEO_KBPS: u32 = 8000; //short clips dont need more than this, anything higher is wasted bytes
+const MIN_VIDEO_KBPS: u32 = 100; //below this it's a slideshow regardless of resolution, we go over the limit instead
+const MAX_ATTEMPTS: u32 = 3;
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(3); //matches yt-dlps --progress-delta
+const GIF_MAX_SECONDS: f64 = 10.0; //longer than this a gif is huge for what it shows, an mp4 is used instead
+const GIF_MAX_WIDTH: u32 = 480;
+const GIF_FPS: u32 = 15;
+/// `-q:v` for each attempt at fitting a jpg under the limit, lower is better, the image is also halved each time.
+const JPEG_QUALITY: &[u32] = &[2, 5, 10];
+
+/// Lowest bitrate each height still looks acceptable at, we only scale down once the budget drops below it.
+const RESOLUTION_LADDER: &[(u32, u32)] = &[(1080, 1500), (720, 700), (480, 350), (360, 200)];
+const FALLBACK_HEIGHT: u32 = 240;
+
+/// Bitrates (in kbps) and resolution cap for an encode that should land under a byte limit.
+#[derive(Clone, Copy, Debug)]
+pub struct EncodePlan {
+    pub video_kbps: u32,
+    pub audio_kbps: u32,
+    pub max_height: u32,
+}
+
+impl EncodePlan {
+    /// `max_height` caps the resolution on top of what the budget allows.
+    pub fn for_target(byte_limit: u64, duration: f64, muted: bool, max_height: u32) -> Self {
+        let total_kbps = budget_kbps(byte_limit, duration);
+
+        let audio_kbps = match total_kbps {
+            _ if muted => 0,
+            1000.. => 128,
+            400.. => 96,
+            200.. => 64,
+            _ => 32,
+        };
+        let video_kbps = total_kbps
+            .saturating_sub(audio_kbps)
+            .clamp(MIN_VIDEO_KBPS, MAX_VIDEO_KBPS);
+
+        Self::new(video_kbps, audio_kbps, max_height)
+    }
+
+    fn new(video_kbps: u32, audio_kbps: u32, max_height: u32) -> Self {
+        let max_height = RESOLUTION_LADDER
+            .iter()
+            .find(|(_, min_kbps)| video_kbps >= *min_kbps)
+            .map_or(FALLBACK_HEIGHT, |(height, _)| *height)
+            .min(max_height);
+
+        Self {
+            video_kbps,
+            audio_kbps,
+            max_height,
+        }
+    }
+
+    /// Shrinks the plan after an encode overshot, by how much it overshot plus a little extra.
+    fn shrink(self, actual: u64, byte_limit: u64) -> Self {
+        let ratio = byte_limit as f64 / actual as f64 * 0.95;
+        let video_kbps = ((f64::from(self.video_kbps) * ratio) as u32).max(MIN_VIDEO_KBPS);
+        Self::new(video_kbps, self.audio_kbps, self.max_height)
+    }
+}
+
+/// Total kbps that fits `byte_limit` over `duration` seconds, after container overhead.
+fn budget_kbps(byte_limit: u64, duration: f64) -> u32 {
+    (byte_limit as f64 * 8.0 * CONTAINER_OVERHEAD / 1000.0 / duration) as u32
+}
+
+/// The parts of `ffprobe -show_format -show_streams` we care about.
+#[derive(Debug, Deserialize)]
+pub struct Probe {
+    pub format: ProbeFormat,
+    #[serde(default)]
+    pub streams: Vec<ProbeStream>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ProbeFormat {
+    pub format_name: String,
+    //ffprobe prints numbers as strings here
+    pub duration: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ProbeStream {
+    pub codec_type: String,
+    pub codec_name: Option<String>,
+    pub pix_fmt: Option<String>,
+    pub height: Option<u32>,
+}
+
+impl Probe {
+    /// Duration in seconds, `None` for streams ffprobe can't time (images, broken files).
+    pub fn duration(&self) -> Option<f64> {
+        self.format
+            .duration
+            .as_deref()?
+            .parse::<f64>()
+            .ok()
+            .filter(|duration| *duration > 0.0)
+    }
+
+    pub fn stream(&self, codec_type: &str) -> Option<&ProbeStream> {
+        self.streams
+            .iter()
+            .find(|stream| stream.codec_type == codec_type)
+    }
+
+    /// Whether discord can play the streams as they are in `format`, so a remux is enough.
+    pub fn is_discord_compatible(&self, muted: bool, format: VideoFormat) -> bool {
+        let (video_codecs, audio_codecs): (&[&str], &[&str]) = match format.container {
+            VideoContainer::Mp4 => (&["h264"], &["aac", "mp3"]),
+            VideoContainer::Webm => (&["vp9", "av1"], &["opus", "vorbis"]),
+        };
+        let video_ok = self.stream("video").is_some_and(|video| {
+            video
+                .codec_name
+                .as_deref()
+                .is_some_and(|codec| video_codecs.contains(&codec))
+                && video.pix_fmt.as_deref().is_none_or(|fmt| fmt == "yuv420p")
+                && video
+                    .height
+                    .is_none_or(|height| height <= format.max_height)
+        });
+        let audio_ok = muted
+            || self.stream("audio").is_none_or(|audio| {
+                audio
+                    .codec_name
+                    .as_deref()
+                    .is_some_and(|codec| audio_codecs.contains(&codec))
+            });
+        video_ok && audio_ok
+    }
+}
+
+pub async fn probe(path: &Path) -> Result<Probe> {
+    let output = ProcessCommand::new("ffprobe")
+        .args([
+            "-v",
+            "error",
+            "-print_format",
+            "json",
+            "-show_format",
+            "-show_streams",
+        ])
+        .arg(path)
+        .output()
+        .await?;
+
+    if !output.status.success() {
+        bail!(
+            "ffprobe exited with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(serde_json::from_slice(&output.stdout)?)
+}
+
+#[derive(Debug)]
+enum Strategy {
+    /// Already playable and small enough, only the container changes.
+    Remux,
+    /// Two pass encode towards a target size.
+    Recode(EncodePlan),
+    /// No duration to budget with, plain quality based encode.
+    Crf,
+}
+
+fn choose_strategy(
+    probe: &Probe,
+    size: u64,
+    byte_limit: u64,
+    muted: bool,
+    format: VideoFormat,
+) -> Strategy {
+    if size <= byte_limit && probe.is_discord_compatible(muted, format) {
+        return Strategy::Remux;
+    }
+    match probe.duration() {
+        Some(duration) => Strategy::Recode(EncodePlan::for_target(
+            byte_limit,
+            duration,
+            muted,
+            format.max_height,
+        )),
+        None => Strategy::Crf,
+    }
+}
+
+/// Forwards ffmpeg's `-progress` output as [`YtDlpEvent::PPProgress`] events.
+pub struct Progress<'a> {
+    events: &'a JobEvents,
+    id: &'a str,
+    duration: Option<f64>,
+    last_sent: Option<Instant>,
+}
+
+/// Where a single ffmpeg run sits in the job's overall progress, each pass of a two pass encode is half.
+#[derive(Clone, Copy)]
+struct Span {
+    start: f64,
+    share: f64,
+}
+
+const WHOLE: Span = Span {
+    start: 0.0,
+    share: 1.0,
+};
+const FIRST_HALF: Span = Span {
+    start: 0.0,
+    share: 0.5,
+};
+const SECOND_HALF: Span = Span {
+    start: 0.5,
+    share: 0.5,
+};
+
+impl<'a> Progress<'a> {
+    pub fn new(events: &'a JobEvents, id: &'a str) -> Self {
+        Self {
+            events,
+            id,
+            duration: None,
+            last_sent: None,
+        }
+    }
+
+    fn report(&mut self, span: Span, position: f64, speed: Option<f64>) {
+        let Some(duration) = self.duration else {
+            return; //cant give a percentage without knowing the length
+        };
+        if self
+            .last_sent
+            .is_some_and(|sent| sent.elapsed() < PROGRESS_INTERVAL)
+        {
+            return;
+        }
+
+        let fraction = (position / duration).clamp(0.0, 1.0);
+        let percent = (span.start + span.share * fraction) * 100.0;
+        //only covers the current run, we dont know how long later passes will take yet
+        let eta = speed
+            .filter(|speed| *speed > 0.0)
+            .map(|speed| format_eta((duration - position).max(0.0) / speed))
+            .unwrap_or_else(|| "--:--".to_string());
+
+        self.events.send(YtDlpEvent::PPProgress {
+            id: self.id.to_string(),
+            percent: format!("{percent:.1}%"),
+            eta,
+        });
+        self.last_sent = Some(Instant::now());
+    }
+}
+
+fn format_eta(seconds: f64) -> String {
+    let seconds = seconds as u64;
+    let (hours, minutes, seconds) = (seconds / 3600, (seconds % 3600) / 60, seconds % 60);
+    if hours > 0 {
+        format!("{hours:02}:{minutes:02}:{seconds:02}")
+    } else {
+        format!("{minutes:02}:{seconds:02}")
+    }
+}
+
+/// Turns a downloaded file into something discord can show that fits under `byte_limit`, remuxing or copying when possible.
+/// `output` is the path without an extension, the one picked for what the file became is returned.
+/// The output is kept even if the final attempt is still too large, the caller decides what to do with it.
+#[allow(clippy::too_many_arguments)]
+pub async fn process(
+    input: &Path,
+    output: &Path,
+    kind: MediaKind,
+    mode: OutputMode,
+    format: VideoFormat,
+    byte_limit: u64,
+    work_dir: &Path,
+    progress: &mut Progress<'_>,
+    cancel: &CancellationToken,
+) -> Result<PathBuf> {
+    fs::create_dir_all(work_dir).await?;
+
+    let probe = probe(input).await?;
+    progress.duration = probe.duration();
+    let size = fs::metadata(input).await?.len();
+
+    match (mode, kind) {
+        (OutputMode::Audio(format), _) => {
+            let output = output.with_extension(mode.extension());
+            process_audio(
+                input, &output, &probe, size, byte_limit, format, progress, cancel,
+            )
+            .await?;
+            Ok(output)
+        }
+        (_, MediaKind::Image) => {
+            process_image(input, output, &probe, size, byte_limit, progress, cancel).await
+        }
+        //a gif with a single frame has nothing to time
+        (_, MediaKind::Animated) if probe.duration().is_none() => {
+            process_image(input, output, &probe, size, byte_limit, progress, cancel).await
+        }
+        (OutputMode::Video, MediaKind::Animated) => {
+            process_animated(
+                input, output, &probe, size, byte_limit, format, work_dir, progress, cancel,
+            )
+            .await
+        }
+        (OutputMode::Video | OutputMode::Muted, _) => {
+            let output = output.with_extension(format.container.extension());
+            let muted = mode == OutputMode::Muted;
+            process_video(
+                input, &output, &probe, size, byte_limit, muted, format, work_dir, progress, cancel,
+            )
+            .await?;
+            Ok(output)
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn process_video(
+    input: &Path,
+    output: &Path,
+    probe: &Probe,
+    size: u64,
+    byte_limit: u64,
+    muted: bool,
+    format: VideoFormat,
+    work_dir: &Path,
+    progress: &mut Progress<'_>,
+    cancel: &CancellationToken,
+) -> Result<()> {
+    if probe.stream("video").is_none() {
+        bail!("{} has no video stream", input.display());
+    }
+
+    let container = format.container;
+    let passlog = work_dir.join("ffmpeg2pass");
+    let strategy = choose_strategy(probe, size, byte_limit, muted, format);
+    debug!("Processing {} with {strategy:?}", input.display());
+
+    let mut plan = match strategy {
+        Strategy::Remux => {
+            let args = remux_args(input, output, muted, container);
+            return run_ffmpeg(&args, progress, WHOLE, cancel).await;
+        }
+        Strategy::Crf => {
+            let args = crf_args(input, output, muted, format);
+            return run_ffmpeg(&args, progress, WHOLE, cancel).await;
+        }
+        Strategy::Recode(plan) => plan,
+    };
+
+    for attempt in 1..=MAX_ATTEMPTS {
+        debug!(
+            "Encoding {} with {plan:?}, attempt {attempt}",
+            input.display()
+        );
+        run_ffmpeg(
+            &first_pass_args(input, &passlog, plan, container),
+            progress,
+            FIRST_HALF,
+            cancel,
+        )
+        .await?;
+        run_ffmpeg(
+            &second_pass_args(input, output, &passlog, plan, muted, container),
+            progress,
+            SECOND_HALF,
+            cancel,
+        )
+        .await?;
+
+        let size = fs::metadata(output).await?.len();
+        if size <= byte_limit || plan.video_kbps == MIN_VIDEO_KBPS {
+            break;
+        }
+        plan = plan.shrink(size, byte_limit);
+    }
+
+    Ok(())
+}
+
+/// Short animations become a looping gif, longer ones or ones where the gif would be too large a silent video.
+#[allow(clippy::too_many_arguments)]
+async fn process_animated(
+    input: &Path,
+    output: &Path,
+    probe: &Probe,
+    size: u64,
+    byte_limit: u64,
+    format: VideoFormat,
+    work_dir: &Path,
+    progress: &mut Progress<'_>,
+    cancel: &CancellationToken,
+) -> Result<PathBuf> {
+    let Some(video) = probe.stream("video") else {
+        bail!("{} has no video stream", input.display());
+    };
+
+    let gif = output.with_extension("gif");
+    if size <= byte_limit && video.codec_name.as_deref() == Some("gif") {
+        fs::copy(input, &gif).await?;
+        return Ok(gif);
+    }
+
+    if fits_gif(probe) {
+        run_ffmpeg(&gif_args(input, &gif), progress, WHOLE, cancel).await?;
+        if fs::metadata(&gif).await?.len() <= byte_limit {
+            return Ok(gif);
+        }
+        debug!(
+            "Gif of {} is over the limit, using a video",
+            input.display()
+        );
+        let _ = fs::remove_file(&gif).await;
+    }
+
+    let video = output.with_extension(format.container.extension());
+    process_video(
+        input, &video, probe, size, byte_limit, true, format, work_dir, progress, cancel,
+    )
+    .await?;
+    Ok(video)
+}
+
+/// Whether the animation is short enough to be worth a gif.
+fn fits_gif(probe: &Probe) -> bool {
+    probe
+        .duration()
+        .is_some_and(|duration| duration <= GIF_MAX_SECONDS)
+}
+
+/// Images discord can show are passed through as is, anything else or anything too large becomes a png or jpg.
+async fn process_image(
+    input: &Path,
+    output: &Path,
+    probe: &Probe,
+    size: u64,
+    byte_limit: u64,
+    progress: &mut Progress<'_>,
+    cancel: &CancellationToken,
+) -> Result<PathBuf> {
+    let Some(image) = probe.stream("video") else {
+        bail!("{} has no image stream", input.display());
+    };
+
+    let passthrough = match image.codec_name.as_deref() {
+        Some("png") => Some("png"),
+        Some("mjpeg") => Some("jpg"),
+        Some("webp") => Some("webp"),
+        Some("gif") => Some("gif"),
+        _ => None,
+    };
+    if size <= byte_limit
+        && let Some(extension) = passthrough
+    {
+        let output = output.with_extension(extension);
+        fs::copy(input, &output).await?;
+        return Ok(output);
+    }
+
+    //transparency only survives as png, everything else is far smaller as a jpg
+    if image.pix_fmt.as_deref().is_some_and(has_alpha) {
+        let png = output.with_extension("png");
+        run_ffmpeg(&image_args(input, &png, None, 1), progress, WHOLE, cancel).await?;
+        if fs::metadata(&png).await?.len() <= byte_limit {
+            return Ok(png);
+        }
+        let _ = fs::remove_file(&png).await;
+    }
+
+    let jpg = output.with_extension("jpg");
+    let mut divisor = 1;
+    for quality in JPEG_QUALITY {
+        debug!(
+            "Converting {} to jpg at quality {quality}, 1/{divisor} size",
+            input.display()
+        );
+        run_ffmpeg(
+            &image_args(input, &jpg, Some(*quality), divisor),
+            progress,
+            WHOLE,
+            cancel,
+        )
+        .await?;
+        if fs::metadata(&jpg).await?.len() <= byte_limit {
+            break;
+        }
+        divisor *= 2;
+    }
+    Ok(jpg)
+}
+
+fn has_alpha(pix_fmt: &str) -> bool {
+    pix_fmt == "pal8" //gifs and some pngs, the palette can hold a transparent colour
+        || pix_fmt.starts_with("ya")
+        || pix_fmt.starts_with("yuva")
+        || ["rgba", "argb", "bgra", "abgr"]
+            .iter()
+            .any(|alpha| pix_fmt.starts_with(alpha))
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn process_audio(
+    input: &Path,
+    output: &Path,
+    probe: &Probe,
+    size: u64,
+    byte_limit: u64,
+    format: AudioFormat,
+    progress: &mut Progress<'_>,
+    cancel: &CancellationToken,
+) -> Result<()> {
+    let Some(audio) = probe.stream("audio") else {
+        bail!("{} has no audio stream", input.display());
+    };
+
+    //already the right codec, just pull it out of the video container
+    if size <= byte_limit && audio.codec_name.as_deref() == Some(format.codec_name()) {
+        debug!(
+            "Extracting audio from {} without re-encoding",
+            input.display()
+        );
+        return run_ffmpeg(
+            &audio_args(input, output, format, None),
+            progress,
+            WHOLE,
+            cancel,
+        )
+        .await;
+    }
+
+    let kbps = audio_kbps(probe, byte_limit, format);
+    debug!("Encoding audio of {} at {kbps}k", input.display());
+
+    run_ffmpeg(
+        &audio_args(input, output, format, Some(kbps)),
+        progress,
+        WHOLE,
+        cancel,
+    )
+    .await
+}
+
+/// Bitrate that fits the audio under `byte_limit`, within what sounds acceptable for `format`.
+fn audio_kbps(probe: &Probe, byte_limit: u64, format: AudioFormat) -> u32 {
+    let (min_kbps, max_kbps) = format.kbps_range();
+    probe
+        .duration()
+        .map_or(max_kbps, |duration| budget_kbps(byte_limit, duration))
+        .clamp(min_kbps, max_kbps)
+}
+
+fn scale_filter(max_height: u32) -> String {
+    //never upscale, -2 keeps the width even which libx264 requires
+    format!("scale=-2:'min({max_height},ih)'")
+}
+
+fn remux_args(input: &Path, output: &Path, muted: bool, container: VideoContainer) -> Vec<String> {
+    let output = output.to_string_lossy();
+    let mut args = input_args(input);
+    args.extend(stream_maps(muted));
+    args.extend(owned(&["-c", "copy"]));
+    args.extend(container_args(container));
+    args.push(output.to_string());
+    args
+}
+
+fn first_pass_args(
+    input: &Path,
+    passlog: &Path,
+    plan: EncodePlan,
+    container: VideoContainer,
+) -> Vec<String> {
+    let passlog = passlog.to_string_lossy();
+    let mut args = input_args(input);
+    args.extend(stream_maps(true));
+    args.extend(video_args(plan, container));
+    args.extend(owned(&[
+        "-pass",
+        "1",
+        "-passlogfile",
+        &passlog,
+        "-f",
+        "null",
+        "-",
+    ]));
+    args
+}
+
+fn second_pass_args(
+    input: &Path,
+    output: &Path,
+    passlog: &Path,
+    plan: EncodePlan,
+    muted: bool,
+    container: VideoContainer,
+) -> Vec<String> {
+    let passlog = passlog.to_string_lossy();
+    let output = output.to_string_lossy();
+    let mut args = input_args(input);
+    args.extend(stream_maps(muted));
+    args.extend(video_args(plan, container));
+    args.extend(owned(&["-pass", "2", "-passlogfile", &passlog]));
+    if !muted {
+        args.extend(owned(&[
+            "-c:a",
+            audio_encoder(container),
+            "-b:a",
+            &format!("{}k", plan.audio_kbps),
+        ]));
+    }
+    args.extend(container_args(container));
+    args.push(output.to_string());
+    args
+}
+
+/// Single pass gif, generating a palette from the clip itself so colours dont band as badly.
+fn gif_args(input: &Path, output: &Path) -> Vec<String> {
+    let output = output.to_string_lossy();
+    let filter = format!(
+        "fps={GIF_FPS},scale='min({GIF_MAX_WIDTH},iw)':-1:flags=lanczos,split[a][b];[a]palettegen[p];[b][p]paletteuse"
+    );
+    let mut args = input_args(input);
+    args.extend(owned(&[
+        "-map", "0:v:0", "-an", "-vf", &filter, "-loop", "0", &output,
+    ]));
+    args
+}
+
+/// First frame only, `divisor` shrinks both sides and `quality` is the jpg `-q:v`.
+fn image_args(input: &Path, output: &Path, quality: Option<u32>, divisor: u32) -> Vec<String> {
+    let output = output.to_string_lossy();
+    let mut args = input_args(input);
+    args.extend(owned(&["-map", "0:v:0", "-frames:v", "1"]));
+    if divisor > 1 {
+        args.extend(owned(&["-vf", &format!("scale=iw/{divisor}:-1")]));
+    }
+    if let Some(quality) = quality {
+        args.extend(owned(&["-q:v", &quality.to_string()]));
+    }
+    args.push(output.to_string());
+    args
+}
+
+fn crf_args(input: &Path, output: &Path, muted: bool, format: VideoFormat) -> Vec<String> {
+    let output = output.to_string_lossy();
+    let mut args = input_args(input);
+    args.extend(stream_maps(muted));
+    args.extend(owned(&["-vf", &scale_filter(format.max_height.min(720))]));
+    match format.container {
+        VideoContainer::Mp4 => args.extend(owned(&[
+            "-c:v",
+            "libx264",
+            "-crf",
+            "28",
+            "-preset",
+            "slow",
+            "-pix_fmt",
+            "yuv420p",
+            "-profile:v",
+            "high",
+        ])),
+        //-b:v 0 makes vp9 purely quality based, its crf scale runs higher than x264s
+        VideoContainer::Webm => args.extend(owned(&[
+            "-c:v",
+            "libvpx-vp9",
+            "-crf",
+            "36",
+            "-b:v",
+            "0",
+            "-row-mt",
+            "1",
+            "-pix_fmt",
+            "yuv420p",
+        ])),
+    }
+    if !muted {
+        args.extend(owned(&[
+            "-c:a",
+            audio_encoder(format.container),
+            "-b:a",
+            "96k",
+        ]));
+    }
+    args.extend(container_args(format.container));
+    args.push(output.to_string());
+    args
+}
+
+/// Copies the audio as is when `kbps` is `None`, otherwise re-encodes it at that bitrate.
+fn audio_args(input: &Path, output: &Path, format: AudioFormat, kbps: Option<u32>) -> Vec<String> {
+    let output = output.to_string_lossy();
+    let mut args = input_args(input);
+    args.extend(owned(&["-map", "0:a:0", "-vn"]));
+    match kbps {
+        Some(kbps) => args.extend(owned(&[
+            "-c:a",
+            format.encoder(),
+            "-b:a",
+            &format!("{kbps}k"),
+        ])),
+        None => args.extend(owned(&["-c:a", "copy"])),
+    }
+    if format == AudioFormat::M4a {
+        args.extend(owned(&["-movflags", "+faststart"]));
+    }
+    args.push(output.to_string());
+    args
+}
+
+fn input_args(input: &Path) -> Vec<String> {
+    owned(&[
+        "-y",
+        "-hide_banner",
+        "-loglevel",
+        "error",
+        "-progress",
+        "pipe:1", //key=value progress blocks on stdout
+        "-nostats",
+        "-i",
+        &input.to_string_lossy(),
+    ])
+}
+
+fn stream_maps(muted: bool) -> Vec<String> {
+    if muted {
+        owned(&["-map", "0:v:0", "-an"])
+    } else {
+        owned(&["-map", "0:v:0", "-map", "0:a:0?"]) //? makes audio optional, some clips have none
+    }
+}
+
+fn video_args(plan: EncodePlan, container: VideoContainer) -> Vec<String> {
+    let bitrate = format!("{}k", plan.video_kbps);
+    let mut args = owned(&["-vf", &scale_filter(plan.max_height)]);
+    match container {
+        VideoContainer::Mp4 => args.extend(owned(&[
+            "-c:v",
+            "libx264",
+            "-b:v",
+            &bitrate,
+            "-preset",
+            "slow",
+            "-pix_fmt",
+            "yuv420p",
+            "-profile:v",
+            "high",
+        ])),
+        //cpu-used 2 is close to the slowest settings in quality at a fraction of the time
+        VideoContainer::Webm => args.extend(owned(&[
+            "-c:v",
+            "libvpx-vp9",
+            "-b:v",
+            &bitrate,
+            "-deadline",
+            "good",
+            "-cpu-used",
+            "2",
+            "-row-mt",
+            "1",
+            "-pix_fmt",
+            "yuv420p",
+        ])),
+    }
+    args
+}
+
+fn audio_encoder(container: VideoContainer) -> &'static str {
+    match container {
+        VideoContainer::Mp4 => "aac",
+        VideoContainer::Webm => "libopus",
+    }
+}
+
+/// Muxer options, mp4 needs its index up front for discord to start playing before it's fully loaded.
+fn container_args(container: VideoContainer) -> Vec<String> {
+    match container {
+        VideoContainer::Mp4 => owned(&["-movflags", "+faststart"]),
+        VideoContainer::Webm => Vec::new(),
+    }
+}
+
+fn owned(args: &[&str]) -> Vec<String> {
+    args.iter().map(|arg| arg.to_string()).collect()
+}
+
+async fn run_ffmpeg(
+    args: &[String],
+    progress: &mut Progress<'_>,
+    span: Span,
+    cancel: &CancellationToken,
+) -> Result<()> {
+    let mut child = ProcessCommand::new("ffmpeg")
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+
+    let stdout = child.stdout.take().expect("piped stdout");
+    let mut stderr = child.stderr.take().expect("piped stderr");
+
+    //drained separately so a chatty stderr can never block ffmpeg while we wait on stdout
+    let stderr_task = tokio::spawn(async move {
+        let mut buffer = String::new();
+        let _ = stderr.read_to_string(&mut buffer).await;
+        buffer
+    });
+
+    let mut lines = BufReader::new(stdout).lines();
+    let mut speed = None;
+
+    loop {
+        let line = tokio::select! {
+            line = lines.next_line() => line?,
+            _ = cancel.cancelled() => bail!("ffmpeg was cancelled"), //dropping the child kills ffmpeg
+        };
+        let Some(line) = line else {
+            break;
+        };
+        let Some((key, value)) = line.split_once('=') else {
+            continue;
+        };
+
+        match key {
+            //speed comes after out_time in each block, so we always use the previous block's speed
+            "speed" => speed = value.trim().trim_end_matches('x').parse::<f64>().ok(),
+            "out_time_us" => {
+                if let Ok(micros) = value.parse::<i64>() {
+                    progress.report(span, micros as f64 / 1_000_000.0, speed);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let status = child.wait().await?;
+    let stderr = stderr_task.await.unwrap_or_default();
+    if !status.success() {
+        bail!("ffmpeg exited with {status}: {}", stderr.trim());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MIB: u64 = 1024 * 1024;
+    const MP4: VideoFormat = VideoFormat {
+        max_height: 1080,
+        container: VideoContainer::Mp4,
+    };
+
+    fn probe(duration: Option<&str>, video: Option<&str>, audio: Option<&str>) -> Probe {
+        let video = video.map(|codec| ProbeStream {
+            codec_type: "video".to_string(),
+            codec_name: Some(codec.to_string()),
+            pix_fmt: Some("yuv420p".to_string()),
+            height: Some(720),
+        });
+        let audio = audio.map(|codec| ProbeStream {
+            codec_type: "audio".to_string(),
+            codec_name: Some(codec.to_string()),
+            pix_fmt: None,
+            height: None,
+        });
+        Probe {
+            format: ProbeFormat {
+                format_name: "mov,mp4,m4a,3gp,3g2,mj2".to_string(),
+                duration: duration.map(ToString::to_string),
+            },
+            streams: video.into_iter().chain(audio).collect(),
+        }
+    }
+
+    /// What an encode following `plan` comes out at, before container overhead.
+    fn planned_bytes(plan: EncodePlan, duration: f64) -> f64 {
+        f64::from(plan.video_kbps + plan.audio_kbps) * 1000.0 / 8.0 * duration
+    }
+
+    #[test]
+    fn plans_fit_the_limit() {
+        for (byte_limit, duration, muted) in [
+            (10 * MIB, 3.0, false),
+            (10 * MIB, 10.0, false),
+            (10 * MIB, 600.0, false),
+            (25 * MIB, 120.0, false),
+            (8 * MIB, 45.0, true),
+            (500 * MIB, 3.0, false),
+        ] {
+            let plan = EncodePlan::for_target(byte_limit, duration, muted, 1080);
+            assert!(
+                planned_bytes(plan, duration) <= byte_limit as f64,
+                "{plan:?} for {duration}s under {byte_limit}"
+            );
+            assert_eq!(plan.audio_kbps == 0, muted, "{plan:?}");
+        }
+    }
+
+    #[test]
+    fn short_clips_are_capped() {
+        let plan = EncodePlan::for_target(10 * MIB, 3.0, false, 720);
+        assert_eq!(plan.video_kbps, MAX_VIDEO_KBPS);
+        assert_eq!(plan.audio_kbps, 128);
+        assert_eq!(plan.max_height, 720, "the requested quality still applies");
+    }
+
+    #[test]
+    fn long_inputs_scale_down() {
+        let plan = EncodePlan::for_target(25 * MIB, 120.0, false, 1080);
+        assert_eq!(plan.max_height, 1080);
+        let plan = EncodePlan::for_target(25 * MIB, 300.0, false, 1080);
+        assert_eq!((plan.audio_kbps, plan.max_height), (96, 480));
+        let plan = EncodePlan::for_target(10 * MIB, 600.0, false, 1080);
+        assert_eq!((plan.audio_kbps, plan.max_height), (32, FALLBACK_HEIGHT));
+
+        //past the floor we go over the limit rather than post a slideshow
+        let plan = EncodePlan::for_target(10 * MIB, 3.0 * 60.0 * 60.0, false, 1080);
+        assert_eq!(plan.video_kbps, MIN_VIDEO_KBPS);
+    }
+
+    #[test]
+    fn shrinking_follows_the_overshoot() {
+        let plan = EncodePlan::for_target(10 * MIB, 120.0, false, 1080);
+        let shrunk = plan.shrink(20 * MIB, 10 * MIB);
+        assert!(shrunk.video_kbps < plan.video_kbps / 2, "{shrunk:?}");
+        assert_eq!(shrunk.audio_kbps, plan.audio_kbps);
+        assert_eq!(shrunk.max_height, 360);
+
+        let floor = plan.shrink(1000 * MIB, 10 * MIB);
+        assert_eq!(floor.video_kbps, MIN_VIDEO_KBPS);
+    }
+
+    #[test]
+    fn remuxes_only_playable_files_under_the_limit() {
+        let playable = probe(Some("60.000000"), Some("h264"), Some("aac"));
+        assert!(matches!(
+            choose_strategy(&playable, 5 * MIB, 10 * MIB, false, MP4),
+            Strategy::Remux
+        ));
+
+        match choose_strategy(&playable, 20 * MIB, 10 * MIB, false, MP4) {
+            Strategy::Recode(plan) => assert!(planned_bytes(plan, 60.0) <= (10 * MIB) as f64),
+            other => panic!("expected a recode, got {other:?}"),
+        }
+
+        let hevc = probe(Some("60.000000"), Some("hevc"), Some("aac"));
+        assert!(matches!(
+            choose_strategy(&hevc, 5 * MIB, 10 * MIB, false, MP4),
+            Strategy::Recode(_)
+        ));
+
+        //opus doesn't play from an mp4, unless the audio is being dropped anyway
+        let opus = probe(Some("60.000000"), Some("h264"), Some("opus"));
+        assert!(matches!(
+            choose_strategy(&opus, 5 * MIB, 10 * MIB, false, MP4),
+            Strategy::Recode(_)
+        ));
+        assert!(matches!(
+            choose_strategy(&opus, 5 * MIB, 10 * MIB, true, MP4),
+            Strategy::Remux
+        ));
+
+        let webm = VideoFormat {
+            max_height: 1080,
+            container: VideoContainer::Webm,
+        };
+        let vp9 = probe(Some("60.000000"), Some("vp9"), Some("opus"));
+        assert!(matches!(
+            choose_strategy(&vp9, 5 * MIB, 10 * MIB, false, webm),
+            Strategy::Remux
+        ));
+
+        let low = VideoFormat {
+            max_height: 480,
+            ..MP4
+        };
+        assert!(matches!(
+            choose_strategy(&playable, 5 * MIB, 10 * MIB, false, low),
+            Strategy::Recode(_)
+        ));
+    }
+
+    #[test]
+    fn untimed_inputs_use_crf() {
+        let untimed = probe(None, Some("hevc"), None);
+        assert!(matches!(
+            choose_strategy(&untimed, 20 * MIB, 10 * MIB, true, MP4),
+            Strategy::Crf
+        ));
+        let broken = probe(Some("N/A"), Some("hevc"), None);
+        assert!(matches!(
+            choose_strategy(&broken, 20 * MIB, 10 * MIB, true, MP4),
+            Strategy::Crf
+        ));
+    }
+
+    #[test]
+    fn audio_fits_the_limit() {
+        for (byte_limit, duration, format) in [
+            (10 * MIB, 1800.0, AudioFormat::Opus),
+            (8 * MIB, 180.0, AudioFormat::Mp3),
+            (25 * MIB, 3600.0, AudioFormat::M4a),
+        ] {
+            let audio = probe(Some(&duration.to_string()), None, Some("flac"));
+            let kbps = audio_kbps(&audio, byte_limit, format);
+            assert!(
+                f64::from(kbps) * 1000.0 / 8.0 * duration <= byte_limit as f64,
+                "{kbps}k for {duration}s under {byte_limit}"
+            );
+        }
+
+        let song = probe(Some("180.0"), None, Some("flac"));
+        assert_eq!(audio_kbps(&song, 8 * MIB, AudioFormat::Mp3), 192);
+        let untimed = probe(None, None, Some("flac"));
+        assert_eq!(audio_kbps(&untimed, 8 * MIB, AudioFormat::Opus), 160);
+        //past the floor it goes over the limit rather than become unlistenable
+        let audiobook = probe(Some("36000.0"), None, Some("flac"));
+        assert_eq!(audio_kbps(&audiobook, 10 * MIB, AudioFormat::Opus), 24);
+    }
+
+    #[test]
+    fn only_short_animations_become_gifs() {
+        assert!(fits_gif(&probe(Some("2.5"), Some("h264"), None)));
+        assert!(fits_gif(&probe(Some("10.000000"), Some("h264"), None)));
+        assert!(!fits_gif(&probe(Some("10.5"), Some("h264"), None)));
+        assert!(!fits_gif(&probe(None, Some("gif"), None)));
+    }
+}
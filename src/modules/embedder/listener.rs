@@ -0,0 +1,221 @@
+use crate::{
+    core::{error::UserError, shutdown},
+    modules::embedder::{
+        config,
+        model::*,
+        pipeline::{self, EmbedJob, EmbedOutcome, StatusMessage},
+        url_policy::host_matches,
+    },
+    prelude::*,
+};
+use futures::StreamExt;
+use poise::serenity_prelude::Context as SerenityContext;
+use regex::Regex;
+use std::{sync::LazyLock, time::Duration};
+
+register_event_listener!(auto_embed);
+
+const MAX_LINKS_PER_MESSAGE: usize = 3; //anything past this is someone dumping links, not sharing a clip
+const OFFER_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// Hosts we auto embed from when `EMBEDDER_AUTO_EMBED_HOSTS` isn't set, subdomains are included.
+const DEFAULT_HOSTS: &[&str] = &[
+    "youtube.com",
+    "youtu.be",
+    "tiktok.com",
+    "twitter.com",
+    "x.com",
+    "instagram.com",
+    "reddit.com",
+    "redd.it",
+    "twitch.tv",
+    "streamable.com",
+    "vimeo.com",
+    "bsky.app",
+];
+
+static URL_PATTERN: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"<?https?://[^\s<>]+>?").expect("valid url regex"));
+
+async fn auto_embed(
+    ctx: FrameworkContext<'_, GlobalState, Error>,
+    event: &FullEvent,
+) -> Result<()> {
+    let FullEvent::MessageCreate(message) = event else {
+        return Ok(());
+    };
+    let Some(guild_id) = message.guild_id else {
+        return Ok(());
+    };
+    if message.author.bot() || shutdown::is_shutting_down() {
+        return Ok(());
+    }
+
+    let links = supported_links(&message.content);
+    if links.is_empty() {
+        return Ok(());
+    }
+
+    let mode = config::auto_embed_mode(guild_id.get(), message.channel_id.get()).await?;
+    if mode == AutoEmbedMode::Off {
+        return Ok(());
+    }
+
+    for url in links {
+        //jobs can run for minutes, dont hold up the other listeners
+        tokio::spawn(handle_link(
+            ctx.serenity_context.clone(),
+            message.clone(),
+            url,
+            mode,
+        ));
+    }
+    Ok(())
+}
+
+/// Links in the message from supported hosts, skipping any the poster wrapped in `<>` to hide the preview.
+fn supported_links(content: &str) -> Vec<Url> {
+    let hosts = match EMBEDDER_AUTO_EMBED_HOSTS.get() {
+        Some(hosts) => hosts
+            .split(',')
+            .map(|host| host.trim().to_lowercase())
+            .filter(|host| !host.is_empty())
+            .collect::<Vec<_>>(),
+        None => DEFAULT_HOSTS.iter().map(ToString::to_string).collect(),
+    };
+
+    extract_links(content, true)
+        .into_iter()
+        .filter(|url| {
+            url.host_str()
+                .is_some_and(|host| hosts.iter().any(|allowed| host_matches(host, allowed)))
+        })
+        .take(MAX_LINKS_PER_MESSAGE)
+        .collect()
+}
+
+/// Every http(s) link in `content`, in order and without duplicates.
+/// `skip_hidden` leaves out links wrapped in `<>`, which is how people opt out of discord's preview.
+pub fn extract_links(content: &str, skip_hidden: bool) -> Vec<Url> {
+    let mut links = Vec::new();
+    for found in URL_PATTERN.find_iter(content).map(|found| found.as_str()) {
+        let hidden = found.starts_with('<') && found.ends_with('>');
+        if hidden && skip_hidden {
+            continue;
+        }
+
+        let link = found.trim_start_matches('<').trim_end_matches('>');
+        //punctuation after a link is almost always part of the sentence
+        let link = link.trim_end_matches(['.', ',', '!', '?', ')', '\'', '"']);
+        if let Ok(url) = Url::parse(link)
+            && !links.contains(&url)
+        {
+            links.push(url);
+        }
+    }
+    links
+}
+
+async fn handle_link(ctx: SerenityContext, message: Message, url: Url, mode: AutoEmbedMode) {
+    let prompt = match mode {
+        AutoEmbedMode::Off => return,
+        AutoEmbedMode::Auto => None,
+        AutoEmbedMode::Button => match offer(&ctx, &message).await {
+            Some(prompt) => Some(prompt),
+            None => return,
+        },
+    };
+
+    let job = EmbedJob {
+        url,
+        mode: OutputMode::Video,
+        quality: None,
+        container: None,
+        clip: None,
+        requester: message.author.id,
+        guild_id: message.guild_id,
+        sent_by: message.author.mention().to_string(),
+        channel_id: message.channel_id,
+        reply_to: None,
+        byte_limit: guild_byte_limit(&ctx.cache, message.guild_id),
+        requested_at: unix_now(),
+    };
+    let mut status = StatusMessage::Message {
+        ctx: &ctx,
+        channel_id: message.channel_id,
+        reply_to: Some(MessageReference::from(&message)),
+        message: prompt,
+    };
+
+    match pipeline::run(&ctx, job, &mut status).await {
+        Ok(EmbedOutcome::Posted) => {
+            //the embed replaces the preview, which is usually a thumbnail or nothing at all
+            let suppress = EditMessage::new().suppress_embeds(true);
+            if let Err(err) = message.clone().edit(&ctx.http, suppress).await {
+                debug!("Failed to suppress embeds on {}: {err}", message.id);
+            }
+        }
+        Ok(EmbedOutcome::Cancelled) => status.linger_and_clear().await,
+        Ok(EmbedOutcome::Interrupted) => {} //the status says it'll be retried, which replaces it
+        Err(err) if err.is::<UserError>() => {
+            status.finish(err.to_string()).await;
+            status.linger_and_clear().await;
+        }
+        Err(err) => {
+            error!("Failed to auto embed from message {}: {err:#}", message.id);
+            status.clear().await;
+        }
+    }
+}
+
+/// Replies with an Embed button and waits for the poster to press it, the prompt is removed if they don't.
+async fn offer(ctx: &SerenityContext, message: &Message) -> Option<Message> {
+    let button_id = format!("auto-embed-{}", uuid::Uuid::new_v4());
+    let prompt = CreateMessage::new()
+        .content("Embed this link?")
+        .components(vec![CreateActionRow::Buttons(
+            vec![
+                CreateButton::new(button_id.clone())
+                    .label("Embed")
+                    .style(ButtonStyle::Primary),
+            ]
+            .into(),
+        )])
+        .reference_message(message)
+        .allowed_mentions(CreateAllowedMentions::new());
+    let prompt = match message.channel_id.send_message(&ctx.http, prompt).await {
+        Ok(prompt) => prompt,
+        Err(err) => {
+            warn!("Failed to offer an embed for message {}: {err}", message.id);
+            return None;
+        }
+    };
+
+    let filter_id = button_id.clone();
+    let mut presses = ComponentInteractionCollector::new(ctx)
+        .filter(move |press| press.data.custom_id.as_str() == filter_id)
+        .timeout(OFFER_TIMEOUT)
+        .stream();
+
+    while let Some(press) = presses.next().await {
+        if press.user.id == message.author.id {
+            press
+                .create_response(&ctx.http, CreateInteractionResponse::Acknowledge)
+                .await
+                .ok();
+            return Some(prompt);
+        }
+
+        let response = CreateInteractionResponse::Message(
+            CreateInteractionResponseMessage::new()
+                .content("Only the person who posted the link can embed it")
+                .ephemeral(true),
+        );
+        if let Err(err) = press.create_response(&ctx.http, response).await {
+            warn!("Failed to respond to embed button {button_id}: {err}");
+        }
+    }
+
+    prompt.delete(&ctx.http, None).await.ok();
+    None
+}
@@ -1,18 +1,44 @@
-use crate::modules::embedder::model::*;
+use crate::core::shutdown;
+use crate::modules::embedder::{
+    model::*,
+    url_policy::{PolicyError, UrlPolicy},
+};
 use crate::prelude::*;
 use anyhow::Context;
 use std::{
+    collections::VecDeque,
+    io::ErrorKind,
     path::{Path, PathBuf},
     process::Stdio,
+    time::Duration,
 };
 use tokio::fs::{self, OpenOptions};
 use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::process::Child;
+use tokio::sync::RwLock;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
 
+mod cache;
 mod commands;
+mod config;
+mod ffmpeg;
+mod listener;
 mod model;
+mod pipeline;
+mod queue;
+mod resume;
+mod storage;
+mod url_policy;
 
 register_startup_listener!(check_deps);
 register_startup_listener!(validate_storage_paths);
+register_shutdown_listener!(stop_jobs);
+
+//both come out of the end of the shutdown grace period, so the whole stop fits inside it
+const KILL_TIMEOUT: Duration = Duration::from_secs(2);
+const STATUS_TIMEOUT: Duration = Duration::from_secs(2);
 
 async fn check_deps() -> Result<()> {
     let yt = async {
@@ -22,19 +48,26 @@ async fn check_deps() -> Result<()> {
             .await
     };
     let ff = async { ProcessCommand::new("ffmpeg").arg("-version").output().await };
+    let fp = async {
+        ProcessCommand::new("ffprobe")
+            .arg("-version")
+            .output()
+            .await
+    };
     let deno = async { ProcessCommand::new("deno").arg("--version").output().await };
 
-    let (yt_res, ff_res, deno_res) = join!(yt, ff, deno);
+    let (yt_res, ff_res, fp_res, deno_res) = join!(yt, ff, fp, deno);
 
     let yt_ok = yt_res.is_ok_and(|o| o.status.success());
     let ff_ok = ff_res.is_ok_and(|o| o.status.success());
+    let fp_ok = fp_res.is_ok_and(|o| o.status.success());
     let deno_ok = deno_res.is_ok_and(|o| o.status.success());
 
     if !deno_ok {
         warn!("Optional Dep Missing: deno, youtube links may fail to embed");
     }
 
-    if yt_ok && ff_ok {
+    if yt_ok && ff_ok && fp_ok {
         Ok(())
     } else {
         let mut missing = Vec::new();
@@ -44,6 +77,9 @@ async fn check_deps() -> Result<()> {
         if !ff_ok {
             missing.push("ffmpeg");
         }
+        if !fp_ok {
+            missing.push("ffprobe");
+        }
         if !deno_ok {
             missing.push("Optional Dep Missing: deno");
         }
@@ -51,67 +87,260 @@ async fn check_deps() -> Result<()> {
     }
 }
 
+const SIZE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+const STDERR_TAIL_LINES: usize = 20; //only the last few lines are useful for classifying errors
+
 pub const BASE_ARGS: &[&str] = &[
     "--no-sponsorblock", // cleaner output
     "--newline",         // one event per line
     "--no-warnings",     // less noise
     "--progress",        // ensure progress ticks
+    //a video that's part of a playlist is just the video, playlist links still get every item
+    "--no-playlist",
+    //skip broken items of a playlist, a single video still fails with its error
+    "--ignore-errors",
     "--progress-delta",
     "3", //only report progress changes every 3 seconds
-    "--format-sort",
-    "vcodec:h264,acodec:m4a,ext:mp4,res:1440,fps",
-    "--recode-video", //we can remux if theres ever a conditional "only recode if remux failed" in future
-    "mp4",
-    // Compression and Dings
-    "--postprocessor-args",
-    "ffmpeg:-vf scale=-2:720 -c:v libx264 -crf 28 -preset slow -pix_fmt yuv420p -profile:v high -c:a aac -b:a 96k -movflags +faststart",
-    //start & progress events for downloading and post-processing
+    //yt-dlp only fetches and merges the streams, probing and encoding happens in ffmpeg.rs
+    "--merge-output-format",
+    "mp4/mkv",
+    //start & progress events for downloading, post-processing events come from our ffmpeg stage
     "--print",
-    r#"before_dl:{"event":"DLStarted","id":"%(id)s"}"#,
+    r#"before_dl:{"event":"DLStarted","id":"%(id)s","extractor":%(extractor_key)j,"filesize":%(filesize,filesize_approx)j,"duration":%(duration)j,"webpage_url":%(webpage_url)j,"urls":%(urls)j,"item":%(playlist_index)j,"items":%(n_entries)j,"ext":%(ext)j,"vcodec":%(vcodec)j,"acodec":%(acodec)j}"#,
     "--progress-template",
     r#"download:{"event":"DLProgress","id":"%(info.id)s","percent":"%(progress._percent_str)s","eta":"%(progress._eta_str)s"}"#,
-    "--print",
-    r#"post_process:{"event":"PPStarted","id":"%(id)s"}"#,
-    "--progress-template",
-    r#"postprocess:{"event":"PPProgress","id":"%(info.id)s","percent":"%(progress._percent_str)s","eta":"%(progress._eta_str)s"}"#,
     //Completion event
     "--print",
-    r#"after_move:{"event":"Finished","id":"%(id)s","path":%(filepath)j}"#,
+    r#"after_move:{"event":"Finished","id":"%(id)s","extractor":%(extractor_key)j,"path":%(filepath)j,"title":%(title)j,"uploader":%(uploader,channel)j,"duration":%(duration)j,"upload_date":%(upload_date)j,"view_count":%(view_count)j,"webpage_url":%(webpage_url)j}"#,
 ];
 
-pub fn yt_dlp_storage_args() -> (String, String) {
-    let home = EMBEDDER_HOME_DIR
+pub fn home_dir() -> PathBuf {
+    EMBEDDER_HOME_DIR
         .get()
         .clone()
-        .unwrap_or_else(|| PathBuf::from(DEFAULT_HOME_DIR));
-    let temp = EMBEDDER_TEMP_DIR
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_HOME_DIR))
+}
+
+pub fn temp_dir() -> PathBuf {
+    EMBEDDER_TEMP_DIR
         .get()
         .clone()
-        .unwrap_or_else(|| PathBuf::from(DEFAULT_TEMP_DIR));
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_TEMP_DIR))
+}
 
-    let home_arg = format!("home:{}", home.to_string_lossy());
-    let temp_arg = format!("temp:{}", temp.to_string_lossy());
+/// Each job gets its own temp dir so its files can be measured and removed without touching other jobs.
+/// yt-dlp keeps everything in there, only the output of our ffmpeg stage ends up in the home dir.
+pub fn yt_dlp_storage_args(job_temp: &Path) -> (String, String) {
+    let home_arg = format!("home:{}", job_temp.to_string_lossy());
+    let temp_arg = format!("temp:{}", job_temp.join("partial").to_string_lossy());
     (home_arg, temp_arg)
 }
 
-async fn download(request: DownloadRequest) -> Result<()> {
+/// Filename safe version of the title, discord shows it on attachments.
+fn output_stem(title: Option<&str>, id: &str) -> String {
+    let stem = title
+        .unwrap_or(id)
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || " -_()[],'!".contains(c) {
+                c
+            } else {
+                '_'
+            }
+        })
+        .take(100)
+        .collect::<String>();
+
+    match stem.trim() {
+        "" => id.to_string(),
+        trimmed => trimmed.to_string(),
+    }
+}
+
+async fn download(
+    request: DownloadRequest,
+    events: &JobEvents,
+    cancel: &CancellationToken,
+) -> Result<()> {
     debug!("Downloading {}", request.url);
     let DownloadRequest {
         url,
-        strip_audio,
-        sender,
+        mode,
+        format,
+        clip,
+        byte_limit,
+        policy,
+        ..
     } = request;
 
+    let size_limit = *EMBEDDER_SIZE_LIMIT.get();
+    let job_id = Uuid::new_v4().to_string();
+    let job_temp = temp_dir().join(&job_id);
+    let job_output = home_dir().join(&job_id);
+
+    let result = async {
+        let Downloads { items, mut missing } = run_yt_dlp(
+            &url, mode, format, clip, &policy, &job_temp, size_limit, events, cancel,
+        )
+        .await?;
+        fs::create_dir_all(&job_output).await?;
+
+        //a link with one item is posted and cached like a plain video, even if yt-dlp saw it as a playlist
+        let multiple = items.len() + missing > 1;
+        let media = match items.as_slice() {
+            [single] if !multiple => single
+                .info
+                .extractor
+                .clone()
+                .map(|extractor| (extractor, single.id.clone())),
+            _ => None,
+        };
+        let info = items.first().map(|first| first.info.clone());
+        let count = items.len();
+        let mut outputs = Vec::with_capacity(count);
+        for (index, downloaded) in items.into_iter().enumerate() {
+            let Downloaded {
+                id,
+                path,
+                info,
+                kind,
+            } = downloaded;
+            let (item, items) = if multiple {
+                (Some(index + 1), Some(count))
+            } else {
+                (None, None)
+            };
+            events.send(YtDlpEvent::PPStarted {
+                id: id.clone(),
+                item,
+                items,
+            });
+
+            let mut stem = output_stem(info.title.as_deref(), &id);
+            if multiple {
+                stem = format!("{} {stem}", index + 1); //items of one post usually share a title
+            }
+            let mut progress = ffmpeg::Progress::new(events, &id);
+            //the extension depends on what the item is encoded into
+            let encoded = ffmpeg::process(
+                Path::new(&path),
+                &job_output.join(stem),
+                kind,
+                mode,
+                format,
+                byte_limit,
+                &job_temp,
+                &mut progress,
+                cancel,
+            )
+            .await;
+            match encoded {
+                Ok(output) => outputs.push(Arc::new(OutputFile::new(output))),
+                Err(err) if cancel.is_cancelled() => return Err(err),
+                Err(err) => {
+                    error!("Failed to encode {path}: {err:#}");
+                    if !multiple {
+                        bail!(YtDlpError::Encode);
+                    }
+                    missing += 1; //the other items are still worth posting
+                }
+            }
+        }
+        if outputs.is_empty() {
+            bail!(YtDlpError::Encode);
+        }
+
+        Ok::<_, Error>(YtDlpEvent::Completed {
+            media,
+            info,
+            outputs,
+            missing,
+        })
+    }
+    .await;
+
+    if let Err(err) = fs::remove_dir_all(&job_temp).await
+        && err.kind() != ErrorKind::NotFound
+    {
+        warn!("Failed to clean up {}: {err}", job_temp.display());
+    }
+
+    let event = match result {
+        Ok(completed) => completed,
+        Err(err) => {
+            //nothing will pick up a partial output
+            let _ = fs::remove_dir_all(&job_output).await;
+
+            if cancel.is_cancelled() {
+                YtDlpEvent::Cancelled
+            } else {
+                let error = err.downcast::<YtDlpError>().unwrap_or_else(|other| {
+                    error!("Failed to run yt-dlp for {url}: {other:#}");
+                    YtDlpError::Unknown { code: None }
+                });
+                YtDlpEvent::Failed { error }
+            }
+        }
+    };
+    events.send(event);
+
+    Ok(())
+}
+
+struct Downloaded {
+    id: String,
+    path: String,
+    info: MediaInfo,
+    kind: MediaKind,
+}
+
+/// What yt-dlp fetched for a link, which can have several items like a playlist or a post with a few videos.
+struct Downloads {
+    /// Never empty, in the order the source had them.
+    items: Vec<Downloaded>,
+    /// Items yt-dlp started on or announced but didn't finish.
+    missing: usize,
+}
+
+/// Runs yt-dlp to completion, forwarding progress events to `events`.
+/// Only the first `EMBEDDER_MAX_ITEMS` items of a link are fetched, a broken item is skipped as long as another one works.
+/// Failures are returned as a [`YtDlpError`] so the caller can tell the user what went wrong.
+#[allow(clippy::too_many_arguments)]
+async fn run_yt_dlp(
+    url: &Url,
+    mode: OutputMode,
+    format: VideoFormat,
+    clip: Option<ClipRange>,
+    policy: &UrlPolicy,
+    job_temp: &Path,
+    size_limit: u64,
+    events: &JobEvents,
+    cancel: &CancellationToken,
+) -> Result<Downloads> {
+    let max_items = EMBEDDER_MAX_ITEMS.get().unwrap_or(DEFAULT_MAX_ITEMS).max(1);
     let mut cmd = ProcessCommand::new("yt-dlp");
-    let (home_arg, temp_arg) = yt_dlp_storage_args();
+    let (home_arg, temp_arg) = yt_dlp_storage_args(job_temp);
     cmd.arg(url.to_string())
         .args(BASE_ARGS)
+        .args(format.format_sort_args())
+        .args(mode.format_args())
         .arg("-P")
         .arg(home_arg)
         .arg("-P")
         .arg(temp_arg)
+        .arg("--playlist-items")
+        .arg(format!("1:{max_items}"))
         .stdout(Stdio::piped())
-        .stderr(Stdio::piped());
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+
+    if let Some(clip) = clip {
+        //the cut lands on the nearest keyframe, our ffmpeg stage re-encodes anyway so it stays playable
+        cmd.arg("--download-sections").arg(clip.download_section());
+    }
+
+    #[cfg(unix)]
+    cmd.process_group(0); //own group so kill_process_tree doesnt take the bot down with it
 
     let mut child = cmd.spawn()?;
 
@@ -122,6 +351,12 @@ async fn download(request: DownloadRequest) -> Result<()> {
     let mut err_lines = BufReader::new(stderr).lines();
 
     let mut stderr_closed = false;
+    let mut stderr_tail = VecDeque::with_capacity(STDERR_TAIL_LINES);
+    let mut size_check = tokio::time::interval(SIZE_CHECK_INTERVAL);
+    let mut downloaded = Vec::new();
+    let mut started = 0;
+    let mut expected = 0;
+    let mut kind = MediaKind::default(); //of the item being downloaded, yt-dlp does them one at a time
 
     loop {
         tokio::select! {
@@ -135,15 +370,51 @@ async fn download(request: DownloadRequest) -> Result<()> {
 
                 debug!("yt-dlp stdout: {}", line);
 
-                if let Ok(event) = serde_json::from_str::<YtDlpEvent>(&line) {
+                if let Ok(mut event) = serde_json::from_str::<YtDlpEvent>(&line) {
                     debug!("yt-dlp event: {:?}", event);
+                    if let YtDlpEvent::DLStarted { item, items, ext, vcodec, acodec, duration, .. } = &mut event {
+                        kind = MediaKind::detect(
+                            ext.as_deref(),
+                            vcodec.as_deref(),
+                            acodec.as_deref(),
+                            *duration,
+                        );
+                        started += 1;
+                        //yt-dlp counts the whole playlist, we only fetch the first few
+                        *items = items.map(|items| items.min(max_items));
+                        *item = item.map(|_| started);
+                        expected = expected.max(items.unwrap_or(1)).max(started);
+                    }
+                    if let YtDlpEvent::DLStarted { webpage_url, urls, .. } = &event
+                        && let Err(err) = check_resolved_urls(policy, webpage_url.as_deref(), urls.as_deref()).await
+                    {
+                        warn!("Blocked download of {url}: {err}");
+                        kill_process_tree(&mut child).await;
+                        bail!(YtDlpError::Blocked(err));
+                    }
+
                     match event {
-                        YtDlpEvent::Finished { id, path } => {
-                            let _ = sender.send(YtDlpEvent::Finished { id, path });
-                            break; // match this variant to break early
+                        YtDlpEvent::DLStarted { duration: Some(duration), .. }
+                            if clip.is_some_and(|clip| clip.start.as_secs_f64() >= duration) =>
+                        {
+                            kill_process_tree(&mut child).await;
+                            bail!(YtDlpError::ClipOutOfRange { duration });
+                        }
+                        YtDlpEvent::DLStarted { filesize: Some(filesize), duration, .. }
+                            if estimated_size(filesize, duration, clip) > size_limit =>
+                        {
+                            //pre-flight, yt-dlp knows (or can guess) the size before fetching anything
+                            kill_process_tree(&mut child).await;
+                            bail!(YtDlpError::TooLarge {
+                                size: estimated_size(filesize, duration, clip),
+                                limit: size_limit,
+                            });
+                        }
+                        YtDlpEvent::Finished { id, path, info } => {
+                            downloaded.push(Downloaded { id, path, info, kind }); //encoded once every item is in
                         }
                         other => {
-                            let _ = sender.send(other);
+                            events.send(other);
                             // send all others for them to handle
                         }
                     }
@@ -154,6 +425,7 @@ async fn download(request: DownloadRequest) -> Result<()> {
                 match stderr_line {
                     Ok(Some(line)) => {
                         debug!("yt-dlp stderr: {}", line);
+                        push_tail(&mut stderr_tail, line);
                     }
                     Ok(None) => {
                         stderr_closed = true;
@@ -164,23 +436,138 @@ async fn download(request: DownloadRequest) -> Result<()> {
                     }
                 }
             }
+
+            _ = cancel.cancelled() => {
+                kill_process_tree(&mut child).await;
+                bail!("download of {url} was cancelled");
+            }
+
+            _ = size_check.tick() => {
+                //the reported size is missing or wrong often enough that we also watch what actually hits the disk
+                let size = dir_size(job_temp).await;
+                if size > size_limit {
+                    warn!("Killing download of {url}, {} exceeds the limit", format_bytes(size));
+                    kill_process_tree(&mut child).await;
+                    bail!(YtDlpError::TooLarge {
+                        size,
+                        limit: size_limit,
+                    });
+                }
+            }
+        }
+    }
+
+    //drain what's left of stderr so a run that produced nothing is classified by the actual error
+    while !stderr_closed {
+        match err_lines.next_line().await {
+            Ok(Some(line)) => push_tail(&mut stderr_tail, line),
+            _ => stderr_closed = true,
+        }
+    }
+
+    let status = child.wait().await?;
+    let stderr = Vec::from(stderr_tail).join("\n");
+    if !downloaded.is_empty() {
+        if !status.success() {
+            warn!("yt-dlp skipped some items of {url}:\n{stderr}");
         }
+        return Ok(Downloads {
+            missing: expected.saturating_sub(downloaded.len()),
+            items: downloaded,
+        });
     }
+    if status.success() {
+        bail!(YtDlpError::NoMedia); //nothing to download, eg an empty playlist
+    }
+
+    warn!("yt-dlp exited with {status} for {url}:\n{stderr}");
+    bail!(YtDlpError::classify(&stderr, status.code()))
+}
+
+/// Kills the child and everything it spawned, yt-dlp runs ffmpeg as its own child which a plain kill would orphan.
+async fn kill_process_tree(child: &mut Child) {
+    #[cfg(unix)]
+    if let Some(pid) = child.id() {
+        // SAFETY: kill has no memory safety requirements, the group was created for this child in run_yt_dlp
+        unsafe {
+            libc::kill(-(pid as i32), libc::SIGKILL);
+        }
+    }
+    let _ = child.kill().await;
+}
 
+fn push_tail(tail: &mut VecDeque<String>, line: String) {
+    if tail.len() == STDERR_TAIL_LINES {
+        tail.pop_front();
+    }
+    tail.push_back(line);
+}
+
+/// Checks the page and formats yt-dlp resolved, the link we were given may have redirected somewhere else.
+async fn check_resolved_urls(
+    policy: &UrlPolicy,
+    webpage_url: Option<&str>,
+    urls: Option<&str>,
+) -> Result<(), PolicyError> {
+    if let Some(webpage_url) = webpage_url.and_then(|link| Url::parse(link).ok()) {
+        policy.check(&webpage_url).await?;
+    }
+
+    let mut checked_hosts = Vec::new();
+    for format_url in urls
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|link| Url::parse(link.trim()).ok())
+    {
+        let host = format_url.host_str().map(ToString::to_string);
+        if checked_hosts.contains(&host) {
+            continue; //formats are almost always served from one or two cdn hosts
+        }
+        policy.check_host(&format_url).await?;
+        checked_hosts.push(host);
+    }
     Ok(())
 }
 
+/// Size yt-dlp will download, the reported filesize is for the whole source so a clip only counts its share.
+fn estimated_size(filesize: f64, duration: Option<f64>, clip: Option<ClipRange>) -> u64 {
+    match (clip, duration) {
+        (Some(clip), Some(duration)) if duration > 0.0 => {
+            (filesize * clip.length(duration) / duration) as u64
+        }
+        (Some(_), _) => 0, //no way to tell how much of it we'll fetch, leave it to the disk check
+        (None, _) => filesize as u64,
+    }
+}
+
+/// Total size of every downloaded file under `path`, unreadable entries are skipped.
+/// yt-dlp merges formats into a `.temp.` file next to the originals, that's a copy of bytes we already counted.
+async fn dir_size(path: &Path) -> u64 {
+    let mut total = 0;
+    let mut pending = vec![path.to_path_buf()];
+
+    while let Some(dir) = pending.pop() {
+        let Ok(mut entries) = fs::read_dir(&dir).await else {
+            continue;
+        };
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            let Ok(metadata) = entry.metadata().await else {
+                continue;
+            };
+            if metadata.is_dir() {
+                pending.push(entry.path());
+            } else if !entry.file_name().to_string_lossy().contains(".temp.") {
+                total += metadata.len();
+            }
+        }
+    }
+
+    total
+}
+
 async fn validate_storage_paths() -> Result<()> {
-    let home_dir = EMBEDDER_HOME_DIR
-        .get()
-        .clone()
-        .unwrap_or_else(|| PathBuf::from(DEFAULT_HOME_DIR));
-    let temp_dir = EMBEDDER_TEMP_DIR
-        .get()
-        .clone()
-        .unwrap_or_else(|| PathBuf::from(DEFAULT_TEMP_DIR));
-    ensure_dir_writable("home", &home_dir).await?;
-    ensure_dir_writable("temp", &temp_dir).await?;
+    ensure_dir_writable("home", &home_dir()).await?;
+    ensure_dir_writable("temp", &temp_dir()).await?;
     Ok(())
 }
 
@@ -201,3 +588,59 @@ async fn ensure_dir_writable(label: &str, path: &Path) -> Result<()> {
     let _ = fs::remove_file(&test_path).await;
     Ok(())
 }
+
+/// Lets running downloads finish within the grace period, then cancels the rest so their embeds say they'll be retried.
+async fn stop_jobs(data: Arc<RwLock<TypeMap>>) -> Result<()> {
+    //missing if we're stopped before the framework finished setting up
+    let Some(embedder_data) = data.read().await.get::<EmbedderDataKey>().cloned() else {
+        return Ok(());
+    };
+    let queue = embedder_data.lock().await.download_queue.clone();
+
+    let deadline = Instant::now() + shutdown::grace_period();
+    //a short grace period is taken from draining first, then from killing
+    let status_deadline = deadline.checked_sub(STATUS_TIMEOUT).unwrap_or(deadline);
+    let kill_deadline = status_deadline
+        .checked_sub(KILL_TIMEOUT)
+        .unwrap_or(status_deadline);
+
+    if tokio::time::timeout_at(kill_deadline, queue.drain())
+        .await
+        .is_err()
+    {
+        info!("Downloads still running after the grace period, cancelling them");
+    }
+    //cancelled jobs kill yt-dlp's process group, and ffmpeg through kill_on_drop, before the queue lets go of them
+    if tokio::time::timeout_at(status_deadline, queue.shutdown())
+        .await
+        .is_err()
+    {
+        warn!("Timed out waiting for downloads to stop");
+    }
+    if tokio::time::timeout_at(deadline, pipeline::wait_for_runs())
+        .await
+        .is_err()
+    {
+        warn!("Timed out waiting for embeds to update their status");
+    }
+
+    remove_job_dirs(&temp_dir()).await;
+    remove_job_dirs(&home_dir()).await;
+    Ok(())
+}
+
+/// Removes what killed jobs left behind in `path`, only the per job dirs are touched in case it's shared with anything else.
+async fn remove_job_dirs(path: &Path) {
+    let Ok(mut entries) = fs::read_dir(path).await else {
+        return;
+    };
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let is_job_dir = entry
+            .file_name()
+            .to_str()
+            .is_some_and(|name| Uuid::parse_str(name).is_ok());
+        if is_job_dir && let Err(err) = fs::remove_dir_all(entry.path()).await {
+            warn!("Failed to remove {}: {err}", entry.path().display());
+        }
+    }
+}
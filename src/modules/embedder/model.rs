@@ -1,112 +1,574 @@
-use std::path::PathBuf;
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Mutex as StdMutex,
+    time::Duration,
+};
 
-use crate::modules::embedder::download;
+use crate::core::settings::SettingValue;
+use crate::modules::embedder::{
+    queue::DownloadQueue,
+    storage::EmbedStorage,
+    url_policy::{PolicyError, UrlPolicy},
+};
 use crate::prelude::*;
-use futures::StreamExt;
-use tokio::{sync::Semaphore, task::JoinHandle};
-use tokio_stream::wrappers::ReceiverStream;
-use tokio_util::sync::CancellationToken;
+use thiserror::Error;
+use tokio::sync::broadcast;
 
 //these are envs instead of a config as they should be set by whoever hosts the bot, not guild owners.
 register_env!(EMBEDDER_CONCURRENCY_LIMIT, usize);
 register_env!(EMBEDDER_SIZE_LIMIT, u64);
 register_env!(EMBEDDER_MAX_QUEUE, Option<usize>);
+register_env!(EMBEDDER_USER_MAX_JOBS, Option<usize>);
+register_env!(EMBEDDER_GUILD_CONCURRENCY_LIMIT, Option<usize>);
+register_env!(EMBEDDER_GUILD_WEIGHTS, Option<Weights>);
+register_env!(EMBEDDER_USER_WEIGHTS, Option<Weights>);
+register_env!(EMBEDDER_MAX_ITEMS, Option<usize>);
 register_env!(EMBEDDER_HOME_DIR, Option<PathBuf>);
 register_env!(EMBEDDER_TEMP_DIR, Option<PathBuf>);
+register_env!(EMBEDDER_AUTO_EMBED_HOSTS, Option<String>);
 
 register_global_data!(init);
 
 pub const DEFAULT_HOME_DIR: &str = "./out";
 pub const DEFAULT_TEMP_DIR: &str = "./tmp";
+pub const DEFAULT_USER_MAX_JOBS: usize = 5;
+pub const DEFAULT_MAX_ITEMS: usize = 10; //a full message of attachments
 
-pub struct DownloadQueue {
-    sender: MPSCSender<DownloadRequest>,
-    handle: JoinHandle<()>,
-    cancel: CancellationToken,
+pub struct EmbedderData {
+    pub download_queue: Arc<DownloadQueue>,
+    pub storage: Option<Arc<EmbedStorage>>,
 }
 
-impl DownloadQueue {
+impl EmbedderData {
     pub fn new() -> Self {
-        let (sender, receiver) = mpsc::channel::<DownloadRequest>(
-            EMBEDDER_MAX_QUEUE
-                .get()
-                .clone()
-                .unwrap_or(Semaphore::MAX_PERMITS),
-        );
+        //already validated by the startup listener, so this only fails if the env changed underneath us
+        let storage = EmbedStorage::from_env()
+            .unwrap_or_else(|err| {
+                error!("Failed to initialise embed storage: {err:#}");
+                None
+            })
+            .map(Arc::new);
 
-        let cancel = CancellationToken::new();
-        let cancel_child = cancel.clone();
+        if let Some(storage) = &storage {
+            storage.clone().spawn_retention_task();
+        }
+
+        Self {
+            download_queue: Arc::new(DownloadQueue::new()),
+            storage,
+        }
+    }
+}
+
+pub struct EmbedderDataKey;
+impl TypeMapKey for EmbedderDataKey {
+    type Value = Arc<Mutex<EmbedderData>>;
+}
+
+fn init(map: &mut TypeMap) {
+    map.insert::<EmbedderDataKey>(Arc::new(Mutex::new(EmbedderData::new())));
+}
+
+/// What a job needs to run, identical requests in flight at the same time share one job.
+#[derive(new)]
+pub struct DownloadRequest {
+    pub url: Url,
+    pub mode: OutputMode,
+    pub format: VideoFormat,
+    /// Only this part of the source is downloaded when set.
+    pub clip: Option<ClipRange>,
+    /// Size the encoded file should fit under, usually the guild's upload limit.
+    pub byte_limit: u64,
+    /// Re-checked against where yt-dlp says the media actually is.
+    pub policy: UrlPolicy,
+    pub owner: JobOwner,
+}
+
+/// Who a job is scheduled for, the queue shares its slots fairly between guilds and the users in them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct JobOwner {
+    pub user_id: UserId,
+    /// `None` for DMs, which are scheduled as if they were one guild.
+    pub guild_id: Option<GuildId>,
+}
 
-        let handle = tokio::spawn(async move {
-            let stream = ReceiverStream::new(receiver).take_until(cancel_child.cancelled());
-            stream
-                .for_each_concurrent(EMBEDDER_CONCURRENCY_LIMIT.get().clone(), |job| async move {
-                    let _ = download(job).await;
-                })
-                .await;
-        });
+/// How many turns in the queue a guild or user gets for every one the others get, by id.
+/// Written as `id=weight` pairs separated by commas, anyone left out has a weight of 1.
+#[derive(Clone, Debug, Default)]
+pub struct Weights(HashMap<u64, u32>);
 
+impl Weights {
+    pub fn get(&self, id: u64) -> u32 {
+        self.0.get(&id).copied().unwrap_or(1)
+    }
+}
+
+impl FromStr for Weights {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut weights = HashMap::new();
+        for pair in s.split(',').map(str::trim).filter(|pair| !pair.is_empty()) {
+            let Some((id, weight)) = pair.split_once('=') else {
+                bail!("expected id=weight, got {pair}");
+            };
+            let id = id.trim().parse::<u64>()?;
+            let weight = weight.trim().parse::<u32>()?;
+            if weight == 0 {
+                bail!("the weight for {id} has to be at least 1");
+            }
+            weights.insert(id, weight);
+        }
+        Ok(Self(weights))
+    }
+}
+
+const EVENT_BUFFER: usize = 16; //progress is sent every few seconds, a consumer only lags if discord stalls it for a while
+
+/// Fans a job's events out to everyone waiting on it, keeping the latest one so late joiners can catch up.
+pub struct JobEvents {
+    sender: broadcast::Sender<YtDlpEvent>,
+    latest: StdMutex<YtDlpEvent>,
+}
+
+impl JobEvents {
+    pub fn new() -> Self {
         Self {
-            sender,
-            handle,
-            cancel,
+            sender: broadcast::channel(EVENT_BUFFER).0,
+            latest: StdMutex::new(YtDlpEvent::Unknown),
         }
     }
 
-    pub async fn enqueue(
-        &self,
-        job: DownloadRequest,
-    ) -> Result<(), mpsc::error::SendError<DownloadRequest>> {
-        self.sender.send(job).await
+    pub fn send(&self, event: YtDlpEvent) {
+        let mut latest = self.latest.lock().expect("job events lock poisoned");
+        *latest = event.clone();
+        let _ = self.sender.send(event); //fails when nobody is listening, which is fine
     }
 
-    pub fn try_enqueue(
-        &self,
-        job: DownloadRequest,
-    ) -> Result<(), mpsc::error::TrySendError<DownloadRequest>> {
-        self.sender.try_send(job)
+    /// Whether the job has sent its final event.
+    pub fn is_finished(&self) -> bool {
+        let latest = self.latest.lock().expect("job events lock poisoned");
+        matches!(
+            *latest,
+            YtDlpEvent::Completed { .. } | YtDlpEvent::Failed { .. } | YtDlpEvent::Cancelled
+        )
     }
 
-    pub async fn shutdown(self) {
-        self.cancel.cancel();
-        drop(self.sender);
-        let _ = self.handle.await; //wait for any tasks to finish
+    /// The latest event and a receiver for everything after it.
+    pub fn subscribe(&self) -> (YtDlpEvent, broadcast::Receiver<YtDlpEvent>) {
+        //holding the lock means no event can slip in between the two
+        let latest = self.latest.lock().expect("job events lock poisoned");
+        (latest.clone(), self.sender.subscribe())
     }
 }
 
-pub struct EmbedderData {
-    pub download_queue: DownloadQueue,
+/// A finished file shared by everyone waiting on its job, deleted along with its directory once the last of them drops it.
+#[derive(Debug)]
+pub struct OutputFile {
+    path: PathBuf,
 }
 
-impl EmbedderData {
-    pub fn new() -> Self {
+impl OutputFile {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for OutputFile {
+    fn drop(&mut self) {
+        if let Err(err) = std::fs::remove_file(&self.path) {
+            error!("Failed to remove file: {}: {err}", self.path.display());
+        }
+        if let Some(dir) = self.path.parent() {
+            let _ = std::fs::remove_dir(dir); //only succeeds once its empty
+        }
+    }
+}
+
+/// Part of the source to download, an open end runs to the end of the source.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ClipRange {
+    pub start: Duration,
+    pub end: Option<Duration>,
+}
+
+impl ClipRange {
+    /// Value for yt-dlp's `--download-sections`, the leading `*` marks it as a time range rather than a chapter name.
+    pub fn download_section(self) -> String {
+        let end = self.end.map_or_else(
+            || "inf".to_string(),
+            |end| format!("{:.3}", end.as_secs_f64()),
+        );
+        format!("*{:.3}-{end}", self.start.as_secs_f64())
+    }
+
+    /// Length of the clip out of a source that is `duration` seconds long.
+    pub fn length(self, duration: f64) -> f64 {
+        let end = self
+            .end
+            .map_or(duration, |end| end.as_secs_f64().min(duration));
+        (end - self.start.as_secs_f64()).max(0.0)
+    }
+}
+
+/// What the job should produce from the source.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum OutputMode {
+    Video,
+    /// Video with the audio stream dropped.
+    Muted,
+    /// Just the audio, for music clips and podcasts.
+    Audio(AudioFormat),
+}
+
+impl OutputMode {
+    /// yt-dlp format selection, skips fetching streams we'd only throw away.
+    pub fn format_args(self) -> &'static [&'static str] {
+        match self {
+            Self::Video => &[],
+            Self::Muted => &["--format", "bv*/b"],
+            Self::Audio(_) => &["--format", "ba/b"],
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Video | Self::Muted => "mp4",
+            Self::Audio(format) => format.extension(),
+        }
+    }
+
+    /// Stable name for storing the mode in the database.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Video => "video",
+            Self::Muted => "muted",
+            Self::Audio(AudioFormat::Opus) => "audio-opus",
+            Self::Audio(AudioFormat::M4a) => "audio-m4a",
+            Self::Audio(AudioFormat::Mp3) => "audio-mp3",
+        }
+    }
+}
+
+impl FromStr for OutputMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "video" => Ok(Self::Video),
+            "muted" => Ok(Self::Muted),
+            "audio-opus" => Ok(Self::Audio(AudioFormat::Opus)),
+            "audio-m4a" => Ok(Self::Audio(AudioFormat::M4a)),
+            "audio-mp3" => Ok(Self::Audio(AudioFormat::Mp3)),
+            other => bail!("unknown output mode {other}"),
+        }
+    }
+}
+
+/// Heights offered for the quality option, anything else would only split the cache further.
+pub const QUALITY_HEIGHTS: &[u32] = &[2160, 1440, 1080, 720, 480, 360, 240];
+pub const DEFAULT_QUALITY: u32 = 1440;
+
+/// `720p` or `720` to a height from [`QUALITY_HEIGHTS`].
+pub fn parse_quality(quality: &str) -> Option<u32> {
+    let quality = quality.trim();
+    let height = quality
+        .strip_suffix(['p', 'P'])
+        .unwrap_or(quality)
+        .parse::<u32>()
+        .ok()?;
+    QUALITY_HEIGHTS.contains(&height).then_some(height)
+}
+
+/// A height from [`QUALITY_HEIGHTS`], as a setting or preference.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Quality(pub u32);
+
+impl SettingValue for Quality {
+    const KIND: &'static str = "a resolution like 720p";
+
+    fn parse_setting(input: &str) -> Result<Self, String> {
+        parse_quality(input).map(Self).ok_or_else(|| {
+            let heights = QUALITY_HEIGHTS
+                .iter()
+                .map(|height| format!("{height}p"))
+                .collect::<Vec<_>>();
+            format!("pick one of {}", heights.join(", "))
+        })
+    }
+
+    fn to_setting(&self) -> String {
+        format!("{}p", self.0)
+    }
+
+    fn suggestions() -> Vec<String> {
+        QUALITY_HEIGHTS
+            .iter()
+            .map(|height| Self(*height).to_setting())
+            .collect()
+    }
+}
+
+/// Container videos are posted in.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, poise::ChoiceParameter)]
+pub enum VideoContainer {
+    #[default]
+    #[name = "mp4"]
+    Mp4,
+    /// VP9 and opus, smaller for the same quality but slower to encode and not every client plays it inline.
+    #[name = "webm"]
+    Webm,
+}
+
+impl VideoContainer {
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Mp4 => "mp4",
+            Self::Webm => "webm",
+        }
+    }
+
+    /// Stable name for storing the container in the database.
+    pub fn as_str(self) -> &'static str {
+        self.extension()
+    }
+}
+
+impl FromStr for VideoContainer {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "mp4" => Ok(Self::Mp4),
+            "webm" => Ok(Self::Webm),
+            other => bail!("unknown container {other}"),
+        }
+    }
+}
+
+impl SettingValue for VideoContainer {
+    const KIND: &'static str = "mp4 or webm";
+
+    fn parse_setting(input: &str) -> Result<Self, String> {
+        input
+            .trim()
+            .to_lowercase()
+            .parse()
+            .map_err(|_| "expected mp4 or webm".to_string())
+    }
+
+    fn to_setting(&self) -> String {
+        self.as_str().to_string()
+    }
+
+    fn suggestions() -> Vec<String> {
+        [Self::Mp4, Self::Webm]
+            .iter()
+            .map(|container| container.to_setting())
+            .collect()
+    }
+}
+
+/// Resolution cap and container for video output, audio jobs always use the default.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct VideoFormat {
+    pub max_height: u32,
+    pub container: VideoContainer,
+}
+
+impl Default for VideoFormat {
+    fn default() -> Self {
         Self {
-            download_queue: DownloadQueue::new(),
+            max_height: DEFAULT_QUALITY,
+            container: VideoContainer::Mp4,
         }
     }
 }
 
-pub struct EmbedderDataKey;
-impl TypeMapKey for EmbedderDataKey {
-    type Value = Arc<Mutex<EmbedderData>>;
+impl VideoFormat {
+    /// yt-dlp format sort, prefers streams we can remux into the container without re-encoding.
+    pub fn format_sort_args(self) -> [String; 2] {
+        let codecs = match self.container {
+            VideoContainer::Mp4 => "vcodec:h264,acodec:m4a,ext:mp4",
+            VideoContainer::Webm => "vcodec:vp9,acodec:opus,ext:webm",
+        };
+        [
+            "--format-sort".to_string(),
+            format!("{codecs},res:{},fps", self.max_height),
+        ]
+    }
+
+    /// Stable name for cache keys.
+    pub fn as_key(self) -> String {
+        format!("{}p-{}", self.max_height, self.container.as_str())
+    }
 }
 
-fn init(map: &mut TypeMap) {
-    map.insert::<EmbedderDataKey>(Arc::new(Mutex::new(EmbedderData::new())));
+/// Extensions yt-dlp gives still images, everything else is treated as video unless it looks animated.
+const IMAGE_EXTENSIONS: &[&str] = &[
+    "jpg", "jpeg", "png", "webp", "bmp", "avif", "heic", "heif", "tif", "tiff",
+];
+const ANIMATED_MAX_SECONDS: f64 = 30.0;
+
+/// What an item is, decides how our ffmpeg stage handles it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MediaKind {
+    #[default]
+    Video,
+    Image,
+    /// Gifs, and the silent clips sites convert them to.
+    Animated,
 }
 
-#[derive(new)]
-pub struct DownloadRequest {
-    pub url: Url,
-    pub strip_audio: bool,
-    pub sender: WatchSender<YtDlpEvent>,
+impl MediaKind {
+    /// Guesses the kind from what yt-dlp reported about the format it picked.
+    pub fn detect(
+        ext: Option<&str>,
+        vcodec: Option<&str>,
+        acodec: Option<&str>,
+        duration: Option<f64>,
+    ) -> Self {
+        let ext = ext.unwrap_or_default().to_lowercase();
+        if ext == "gif" || vcodec == Some("gif") {
+            return Self::Animated;
+        }
+        if IMAGE_EXTENSIONS.contains(&ext.as_str()) {
+            return Self::Image;
+        }
+        //twitter, imgur and the like serve gifs as mp4s, the only tell is a short clip without any audio
+        let silent = acodec == Some("none") && vcodec.is_some_and(|vcodec| vcodec != "none");
+        if silent && duration.is_some_and(|duration| duration <= ANIMATED_MAX_SECONDS) {
+            return Self::Animated;
+        }
+        Self::Video
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, poise::ChoiceParameter)]
+pub enum AudioFormat {
+    #[name = "opus"]
+    Opus,
+    #[name = "m4a"]
+    M4a,
+    #[name = "mp3"]
+    Mp3,
+}
+
+impl AudioFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Opus => "ogg",
+            Self::M4a => "m4a",
+            Self::Mp3 => "mp3",
+        }
+    }
+
+    /// Name ffprobe reports for this codec, used to skip re-encoding when the source already matches.
+    pub fn codec_name(self) -> &'static str {
+        match self {
+            Self::Opus => "opus",
+            Self::M4a => "aac",
+            Self::Mp3 => "mp3",
+        }
+    }
+
+    pub fn encoder(self) -> &'static str {
+        match self {
+            Self::Opus => "libopus",
+            Self::M4a => "aac",
+            Self::Mp3 => "libmp3lame",
+        }
+    }
+
+    /// Bitrate range in kbps, the top is where more bits stop being audible and the bottom is where speech still holds up.
+    pub fn kbps_range(self) -> (u32, u32) {
+        match self {
+            Self::Opus => (24, 160),
+            Self::M4a | Self::Mp3 => (48, 192),
+        }
+    }
+}
+
+/// What happens to supported links posted in a channel.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, poise::ChoiceParameter)]
+pub enum AutoEmbedMode {
+    #[default]
+    #[name = "off"]
+    Off,
+    /// Reply with a button the poster can press to embed it.
+    #[name = "button"]
+    Button,
+    #[name = "auto"]
+    Auto,
+}
+
+impl AutoEmbedMode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Off => "off",
+            Self::Button => "button",
+            Self::Auto => "auto",
+        }
+    }
+}
+
+impl FromStr for AutoEmbedMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "off" => Ok(Self::Off),
+            "button" => Ok(Self::Button),
+            "auto" => Ok(Self::Auto),
+            other => bail!("unknown auto embed mode {other}"),
+        }
+    }
 }
 
 #[derive(Clone, Debug, Deserialize)]
 #[serde(tag = "event")]
 pub enum YtDlpEvent {
+    /// Sent whenever the job's place in the queue changes, stops once it starts downloading.
+    #[serde(skip_deserializing)]
+    Queued {
+        position: usize,
+        estimated_wait: Option<Duration>,
+    },
     DLStarted {
         id: String,
+        /// yt-dlp's name for the site, together with the id it identifies the media.
+        #[serde(default)]
+        extractor: Option<String>,
+        //approximate for most sites, missing for some
+        #[serde(default)]
+        filesize: Option<f64>,
+        /// Length of the whole source in seconds, missing for livestreams and some sites.
+        #[serde(default)]
+        duration: Option<f64>,
+        /// Page the extractor ended up on, after any redirects.
+        #[serde(default)]
+        webpage_url: Option<String>,
+        /// Newline separated urls of the formats about to be downloaded.
+        #[serde(default)]
+        urls: Option<String>,
+        /// Which item this is when the link has several, counted from 1. `None` for a single video.
+        #[serde(default)]
+        item: Option<usize>,
+        /// How many items will be downloaded, capped at `EMBEDDER_MAX_ITEMS`.
+        #[serde(default)]
+        items: Option<usize>,
+        /// Extension and codecs of the format yt-dlp picked, what [`MediaKind::detect`] goes off.
+        #[serde(default)]
+        ext: Option<String>,
+        #[serde(default)]
+        vcodec: Option<String>,
+        #[serde(default)]
+        acodec: Option<String>,
     },
     DLProgress {
         id: String,
@@ -115,15 +577,43 @@ pub enum YtDlpEvent {
     },
     PPStarted {
         id: String,
+        #[serde(default)]
+        item: Option<usize>,
+        #[serde(default)]
+        items: Option<usize>,
     },
     PPProgress {
         id: String,
         percent: String,
         eta: String,
     },
+    /// yt-dlp is done with an item, only seen by the download itself as the item still has to be encoded.
     Finished {
         id: String,
         path: String,
+        #[serde(flatten)]
+        info: MediaInfo,
+    },
+    /// Every item was downloaded and encoded, consumers hold on to the files until they've posted them.
+    #[serde(skip_deserializing)]
+    Completed {
+        /// Extractor and id of the media, `None` for links with several items as those aren't cached.
+        media: Option<(String, String)>,
+        /// Of the first item, posts with several usually share their title and uploader.
+        info: Option<MediaInfo>,
+        /// In the order the source had them.
+        outputs: Vec<Arc<OutputFile>>,
+        /// Items that were skipped because they failed to download or encode.
+        missing: usize,
+    },
+    /// The job was cancelled by the user before it finished.
+    #[serde(skip_deserializing)]
+    Cancelled,
+
+    /// The download ended without producing a file, always the last event sent for a job.
+    #[serde(skip_deserializing)]
+    Failed {
+        error: YtDlpError,
     },
 
     // Unknown/forward-compat events fall here instead of erroring
@@ -131,6 +621,315 @@ pub enum YtDlpEvent {
     Unknown,
 }
 
+const MAX_TITLE_CHARS: usize = 100;
+
+/// What yt-dlp told us about an item, shown as a caption above it when the guild hasn't turned those off.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct MediaInfo {
+    /// yt-dlp's name for the site, together with the id it identifies the media.
+    #[serde(default)]
+    pub extractor: Option<String>,
+    #[serde(default)]
+    pub title: Option<String>,
+    #[serde(default)]
+    pub uploader: Option<String>,
+    /// Length in seconds.
+    #[serde(default)]
+    pub duration: Option<f64>,
+    /// `YYYYMMDD`, as yt-dlp gives it.
+    #[serde(default)]
+    pub upload_date: Option<String>,
+    #[serde(default)]
+    pub view_count: Option<u64>,
+    /// Page the extractor ended up on, after any redirects.
+    #[serde(default)]
+    pub webpage_url: Option<String>,
+}
+
+impl MediaInfo {
+    /// The title in bold with whatever else we know in subtext under it, `None` if yt-dlp gave us nothing.
+    pub fn caption(&self) -> Option<String> {
+        let mut details = Vec::new();
+        if let Some(uploader) = &self.uploader {
+            details.push(escape_markdown(uploader));
+        }
+        if let Some(duration) = self
+            .duration
+            .and_then(|duration| Duration::try_from_secs_f64(duration).ok())
+        {
+            details.push(format_duration(duration));
+        }
+        if let Some(views) = self.view_count {
+            details.push(format!("{} views", format_count(views)));
+        }
+        if let Some(date) = self.upload_date.as_deref().and_then(format_upload_date) {
+            details.push(date);
+        }
+        if let Some(extractor) = &self.extractor {
+            details.push(escape_markdown(extractor));
+        }
+
+        let mut lines = Vec::new();
+        if let Some(title) = self
+            .title
+            .as_deref()
+            .filter(|title| !title.trim().is_empty())
+        {
+            let mut shortened = title.chars().take(MAX_TITLE_CHARS).collect::<String>();
+            if shortened.len() < title.len() {
+                shortened.push('…');
+            }
+            lines.push(format!("**{}**", escape_markdown(shortened.trim())));
+        }
+        if !details.is_empty() {
+            lines.push(format!("-# {}", details.join(" • ")));
+        }
+        (!lines.is_empty()).then(|| lines.join("\n"))
+    }
+}
+
+/// `20240131` to `2024-01-31`, `None` for anything that isn't a yt-dlp date.
+fn format_upload_date(date: &str) -> Option<String> {
+    if date.len() != 8 || !date.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+    Some(format!("{}-{}-{}", &date[..4], &date[4..6], &date[6..]))
+}
+
+/// Why a download failed, the display text is shown to the user as is.
+#[derive(Clone, Debug, Error, PartialEq)]
 pub enum YtDlpError {
-    Unknown,
+    #[error("that site isn't supported")]
+    UnsupportedUrl,
+    #[error("that video is private")]
+    Private,
+    #[error("that video is unavailable, it may have been removed")]
+    Unavailable,
+    #[error("that video isn't available in the bot's region")]
+    GeoBlocked,
+    #[error("that video is age restricted")]
+    AgeRestricted,
+    #[error("that video requires an account to view")]
+    LoginRequired,
+    #[error("the site is rate limiting the bot, try again later")]
+    RateLimited,
+    #[error("no downloadable media was found at that link")]
+    NoMedia,
+    #[error("the site couldn't be reached, try again later")]
+    Network,
+    #[error("{0}")]
+    Blocked(PolicyError),
+    #[error("it exceeds the download limit of {}, it is at least {}", format_bytes(*.limit), format_bytes(*.size))]
+    TooLarge { size: u64, limit: u64 },
+    #[error("the clip starts after the end of the video{}", video_length(*.duration))]
+    ClipOutOfRange { duration: f64 },
+    #[error("the video couldn't be converted into something discord can play")]
+    Encode,
+    #[error("the download failed for an unknown reason")]
+    Unknown { code: Option<i32> },
+}
+
+/// ", which is only 3:12", or nothing when yt-dlp reported a length we can't use.
+fn video_length(seconds: f64) -> String {
+    Duration::try_from_secs_f64(seconds)
+        .map(|duration| format!(", which is only {}", format_duration(duration)))
+        .unwrap_or_default()
+}
+
+impl YtDlpError {
+    /// Classifies a failed run from what yt-dlp printed to stderr.
+    /// Order matters, youtubes bot check mentions signing in but is really a rate limit.
+    pub fn classify(stderr: &str, code: Option<i32>) -> Self {
+        let stderr = stderr.to_lowercase();
+        let mentions = |needles: &[&str]| needles.iter().any(|needle| stderr.contains(needle));
+
+        if mentions(&["unsupported url"]) {
+            Self::UnsupportedUrl
+        } else if mentions(&[
+            "confirm your age",
+            "age-restricted",
+            "age restricted",
+            "inappropriate for some users",
+        ]) {
+            Self::AgeRestricted
+        } else if mentions(&["private video", "video is private", "account is private"]) {
+            Self::Private
+        } else if mentions(&[
+            "not available in your country",
+            "made this video available in your country",
+            "not available from your location",
+            "geo restriction",
+            "geo-restricted",
+            "geo restricted",
+        ]) {
+            Self::GeoBlocked
+        } else if mentions(&[
+            "http error 429",
+            "too many requests",
+            "rate-limit",
+            "rate limit",
+            "not a bot",
+        ]) {
+            Self::RateLimited
+        } else if mentions(&[
+            //yt-dlp's own wording, anything looser matches page titles and unrelated errors
+            "login required",
+            "sign in to confirm",
+            "only available for registered users",
+            "you need to log in",
+            "members-only content",
+            "available to this channel's members",
+        ]) {
+            Self::LoginRequired
+        } else if mentions(&[
+            "video unavailable",
+            "has been removed",
+            "no longer available",
+            "does not exist",
+            "has been terminated",
+            "http error 404",
+        ]) {
+            Self::Unavailable
+        } else if mentions(&[
+            "requested format is not available",
+            "no video formats found",
+            "no media found",
+            "there's no video",
+            "no video could be found",
+        ]) {
+            Self::NoMedia
+        } else if mentions(&[
+            "unable to download webpage",
+            "timed out",
+            "connection reset",
+            "name or service not known",
+            "temporary failure in name resolution",
+            "network is unreachable",
+        ]) {
+            Self::Network
+        } else {
+            Self::Unknown { code }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classifies_yt_dlp_errors() {
+        use YtDlpError::*;
+        let cases = [
+            (
+                "ERROR: Unsupported URL: https://example.com/about",
+                UnsupportedUrl,
+            ),
+            (
+                "ERROR: [youtube] dQw4w9WgXcQ: Sign in to confirm your age. This video may be inappropriate for some users. Use --cookies-from-browser or --cookies for the authentication.",
+                AgeRestricted,
+            ),
+            (
+                "ERROR: [youtube] dQw4w9WgXcQ: Private video. Sign in if you've been granted access to this video. Use --cookies-from-browser or --cookies for the authentication.",
+                Private,
+            ),
+            (
+                "ERROR: [youtube] dQw4w9WgXcQ: Video unavailable. The uploader has not made this video available in your country",
+                GeoBlocked,
+            ),
+            (
+                "ERROR: [youtube] dQw4w9WgXcQ: Sign in to confirm you\u{2019}re not a bot. Use --cookies-from-browser or --cookies for the authentication.",
+                RateLimited,
+            ),
+            (
+                "ERROR: [youtube] dQw4w9WgXcQ: Unable to download API page: HTTP Error 429: Too Many Requests (caused by <HTTPError 429: Too Many Requests>)",
+                RateLimited,
+            ),
+            (
+                "ERROR: [vimeo] 76979871: This video is only available for registered users. Use --cookies-from-browser or --cookies for the authentication.",
+                LoginRequired,
+            ),
+            (
+                "ERROR: [youtube] dQw4w9WgXcQ: Join this channel to get access to members-only content like this video, and other exclusive perks.",
+                LoginRequired,
+            ),
+            (
+                "ERROR: [youtube] dQw4w9WgXcQ: Video unavailable. This video has been removed by the uploader",
+                Unavailable,
+            ),
+            (
+                "ERROR: [generic] Unable to download webpage: HTTP Error 404: Not Found (caused by <HTTPError 404: Not Found>)",
+                Unavailable,
+            ),
+            (
+                "ERROR: [twitter] 1234567890: No video could be found in this tweet",
+                NoMedia,
+            ),
+            (
+                "ERROR: [youtube] dQw4w9WgXcQ: Requested format is not available. Use --list-formats for a list of available formats",
+                NoMedia,
+            ),
+            (
+                "ERROR: [generic] Unable to download webpage: <urlopen error [Errno -2] Name or service not known> (caused by TransportError('<urlopen error [Errno -2] Name or service not known>'))",
+                Network,
+            ),
+            //close to the login phrases without being them
+            (
+                "ERROR: [generic] How to sign in to your account: Unable to extract title",
+                Unknown { code: Some(1) },
+            ),
+            (
+                "ERROR: Unable to log in to proxy: connection refused",
+                Unknown { code: Some(1) },
+            ),
+            ("", Unknown { code: Some(1) }),
+        ];
+
+        for (stderr, expected) in cases {
+            assert_eq!(YtDlpError::classify(stderr, Some(1)), expected, "{stderr}");
+        }
+    }
+
+    #[test]
+    fn parses_weights() {
+        let weights = " 10=3, 20 = 2,".parse::<Weights>().unwrap();
+        assert_eq!(
+            (weights.get(10), weights.get(20), weights.get(30)),
+            (3, 2, 1)
+        );
+        for invalid in ["10", "10=0", "ten=2", "10=-1"] {
+            assert!(invalid.parse::<Weights>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn detects_media_kinds() {
+        use MediaKind::*;
+        const NONE: Option<&str> = Some("none"); //what yt-dlp reports for a missing stream
+        let cases = [
+            (Some("gif"), Some("gif"), NONE, Some(4.0), Animated),
+            (Some("GIF"), None, None, None, Animated),
+            (Some("webm"), Some("gif"), NONE, Some(120.0), Animated),
+            (Some("jpg"), Some("mjpeg"), NONE, None, Image),
+            (Some("PNG"), None, None, None, Image),
+            //sites serve gifs as silent mp4s, up to 30s of one is still treated as a gif
+            (Some("mp4"), Some("h264"), NONE, Some(6.0), Animated),
+            (Some("mp4"), Some("h264"), NONE, Some(30.0), Animated),
+            (Some("mp4"), Some("h264"), NONE, Some(30.5), Video),
+            (Some("mp4"), Some("h264"), NONE, None, Video),
+            (Some("mp4"), Some("h264"), Some("aac"), Some(6.0), Video),
+            //an unknown audio codec could still be audio
+            (Some("mp4"), Some("h264"), None, Some(6.0), Video),
+            (Some("m4a"), NONE, Some("aac"), Some(6.0), Video),
+            (None, None, None, None, Video),
+        ];
+
+        for (ext, vcodec, acodec, duration, expected) in cases {
+            assert_eq!(
+                MediaKind::detect(ext, vcodec, acodec, duration),
+                expected,
+                "{ext:?} {vcodec:?} {acodec:?} {duration:?}"
+            );
+        }
+    }
 }
@@ -0,0 +1,728 @@
+use crate::{
+    modules::embedder::{
+        cache::{CacheLookup, CachedEmbed},
+        config,
+        model::*,
+        queue::{QueueError, Subscription},
+        resume::{JobState, PersistedJob},
+        url_policy::{HostRules, UrlPolicy},
+    },
+    prelude::*,
+};
+use futures::StreamExt;
+use poise::serenity_prelude::Context as SerenityContext;
+use std::{path::Path, sync::LazyLock};
+use tokio::{fs, sync::broadcast::error::RecvError};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+use uuid::Uuid;
+
+const MAX_ATTACHMENTS: usize = 10; //discords limit per message
+
+/// Every embed that's being run, so shutdown can wait for them to leave a final status.
+static RUNS: LazyLock<TaskTracker> = LazyLock::new(TaskTracker::new);
+
+/// A link to embed and who asked for it, shared by the embed command and the auto embed listener.
+pub struct EmbedJob {
+    pub url: Url,
+    pub mode: OutputMode,
+    /// Resolution cap the requester asked for, the guild's default otherwise.
+    pub quality: Option<u32>,
+    /// Falls back to mp4, webm has to be allowed by the guild.
+    pub container: Option<VideoContainer>,
+    pub clip: Option<ClipRange>,
+    pub requester: UserId,
+    pub guild_id: Option<GuildId>,
+    /// Shown in the "sent by" line, a mention or `anon`.
+    pub sent_by: String,
+    pub channel_id: GenericChannelId,
+    /// Posts the result as a reply to this message instead of a plain message in the channel.
+    pub reply_to: Option<MessageReference>,
+    pub byte_limit: u64,
+    /// Unix time the embed was asked for, carried over when it's retried after a restart.
+    pub requested_at: u64,
+}
+
+impl EmbedJob {
+    /// The posted message, `caption` goes above the sent by line.
+    fn result_message(&self, caption: Option<&str>, content: String) -> CreateMessage<'static> {
+        let content = match caption {
+            Some(caption) => format!("{caption}\n{content}"),
+            None => content,
+        };
+        let message = CreateMessage::new().content(content);
+        match &self.reply_to {
+            Some(reference) => message.reference_message(reference.clone()),
+            None => message,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EmbedOutcome {
+    /// The media was posted, either attached or as a link to storage.
+    Posted,
+    Cancelled,
+    /// The bot is shutting down, the job is retried once it's back.
+    Interrupted,
+}
+
+/// Where a job shows its progress while it runs.
+pub enum StatusMessage<'a> {
+    /// The ephemeral reply of the embed command.
+    Reply {
+        ctx: Context<'a>,
+        handle: Option<ReplyHandle<'a>>,
+    },
+    /// A message in the job's channel, for jobs the bot runs on its own like auto embeds and resumed ones.
+    Message {
+        ctx: &'a SerenityContext,
+        channel_id: GenericChannelId,
+        /// Usually the message the link came from.
+        reply_to: Option<MessageReference>,
+        message: Option<Message>,
+    },
+}
+
+impl StatusMessage<'_> {
+    /// Shows the first status of a job along with its cancel button.
+    async fn start(&mut self, content: &str, button_id: &str) {
+        match self {
+            Self::Reply { ctx, handle } => {
+                *handle = ctx
+                    .send(
+                        CreateReply::new()
+                            .content(content)
+                            .components(vec![cancel_button(button_id)])
+                            .reply(true),
+                    )
+                    .await
+                    .ok();
+            }
+            Self::Message {
+                ctx,
+                channel_id,
+                reply_to,
+                message,
+            } => {
+                if let Some(message) = message {
+                    let edit = EditMessage::new()
+                        .content(content)
+                        .components(vec![cancel_button(button_id)]);
+                    if message.edit(&ctx.http, edit).await.is_ok() {
+                        return;
+                    }
+                }
+                let reply = status_reply(content, reply_to.clone())
+                    .components(vec![cancel_button(button_id)]);
+                *message = channel_id.send_message(&ctx.http, reply).await.ok();
+            }
+        }
+    }
+
+    /// Replaces the status text, keeping whatever buttons it already has.
+    pub async fn update(&mut self, content: impl Into<String>) {
+        match self {
+            Self::Reply { ctx, handle } => {
+                *handle = edit_or_send_new(ctx, handle.take(), content).await.ok();
+            }
+            Self::Message {
+                ctx,
+                channel_id,
+                reply_to,
+                message,
+            } => {
+                let content = content.into();
+                if let Some(message) = message
+                    && message
+                        .edit(&ctx.http, EditMessage::new().content(&content))
+                        .await
+                        .is_ok()
+                {
+                    return;
+                }
+                let reply = status_reply(content, reply_to.clone());
+                *message = channel_id.send_message(&ctx.http, reply).await.ok();
+            }
+        }
+    }
+
+    /// Leaves a final status without any buttons, for outcomes that aren't an error.
+    pub async fn finish(&mut self, content: impl Into<String>) {
+        let content = content.into();
+        if let Self::Message {
+            ctx,
+            message: Some(message),
+            ..
+        } = self
+        {
+            let edit = EditMessage::new().content(&content).components(vec![]);
+            if message.edit(&ctx.http, edit).await.is_ok() {
+                return;
+            }
+        }
+        self.update(content).await;
+    }
+
+    pub async fn clear(&mut self) {
+        match self {
+            Self::Reply { ctx, handle } => {
+                handle.delete(*ctx).await.ok();
+                *handle = None;
+            }
+            Self::Message { ctx, message, .. } => {
+                if let Some(message) = message.take() {
+                    message.delete(&ctx.http, None).await.ok();
+                }
+            }
+        }
+    }
+
+    /// Leaves the status up long enough to read before it cleans itself up.
+    pub async fn linger_and_clear(&mut self) {
+        tokio::time::sleep(STATUS_LINGER).await;
+        self.clear().await;
+    }
+
+    /// The status message and what it replies to, only channel messages can be picked up again after a restart.
+    fn resumable(&self) -> (Option<MessageId>, Option<MessageId>) {
+        match self {
+            Self::Reply { .. } => (None, None),
+            Self::Message {
+                reply_to, message, ..
+            } => (
+                message.as_ref().map(|message| message.id),
+                reply_to.as_ref().and_then(|reference| reference.message_id),
+            ),
+        }
+    }
+}
+
+fn status_reply(
+    content: impl Into<String>,
+    reply_to: Option<MessageReference>,
+) -> CreateMessage<'static> {
+    let reply = CreateMessage::new()
+        .content(content.into())
+        .allowed_mentions(CreateAllowedMentions::new());
+    match reply_to {
+        Some(reference) => reply.reference_message(reference),
+        None => reply,
+    }
+}
+
+/// Queues the job and reports on it through `status` until the result is posted in the job's channel.
+/// Failures the user should see are returned as a [`crate::core::error::UserError`], the status is cleared before that.
+pub async fn run(
+    ctx: &SerenityContext,
+    job: EmbedJob,
+    status: &mut StatusMessage<'_>,
+) -> Result<EmbedOutcome> {
+    RUNS.track_future(run_job(ctx, job, status)).await
+}
+
+/// Waits for every running embed to return, which they do soon after the queue shuts down.
+pub async fn wait_for_runs() {
+    RUNS.close();
+    RUNS.wait().await;
+}
+
+async fn run_job(
+    ctx: &SerenityContext,
+    job: EmbedJob,
+    status: &mut StatusMessage<'_>,
+) -> Result<EmbedOutcome> {
+    let guild_rules = match job.guild_id {
+        Some(guild_id) => config::host_rules(guild_id.get()).await?,
+        None => HostRules::default(),
+    };
+    let policy = UrlPolicy::with_guild_rules(guild_rules);
+    if let Err(err) = policy.check(&job.url).await {
+        bail_to_user!("Can't embed [[link]](<{}>): {err}", job.url);
+    }
+    let captions = match job.guild_id {
+        Some(guild_id) => config::EMBED_CAPTIONS.get(guild_id).await?,
+        None => true,
+    };
+    let format = video_format(&job).await?;
+
+    let mut cache = CacheLookup::new(&job.url, job.mode, format, job.clip, job.byte_limit);
+    if let Some(cached) = cache.find_source(&policy).await {
+        post_cached(ctx, &job, &cached, captions).await;
+        return Ok(EmbedOutcome::Posted);
+    }
+
+    let embedder_data = {
+        let data = ctx.data.read().await;
+        data.get::<EmbedderDataKey>()
+            .expect("Embedder data not found")
+            .clone()
+    };
+
+    let request = DownloadRequest {
+        url: job.url.clone(),
+        mode: job.mode,
+        format,
+        clip: job.clip,
+        byte_limit: job.byte_limit,
+        policy: policy.clone(),
+        owner: JobOwner {
+            user_id: job.requester,
+            guild_id: job.guild_id,
+        },
+    };
+
+    let enqueued = embedder_data
+        .lock()
+        .await
+        .download_queue
+        .try_enqueue(request);
+    let Subscription {
+        latest,
+        mut events,
+        cancel,
+        ..
+    } = match enqueued {
+        Ok(subscription) => subscription,
+        Err(QueueError::UserLimit { queued }) => {
+            bail_to_user!("You already have {queued} embeds queued, wait for one to finish first")
+        }
+        Err(QueueError::Closed) => bail_to_user!("The bot is restarting, try again in a minute"),
+        Err(QueueError::Full) => {
+            bail_to_user!("Failed to queue download, server might be overloaded")
+        }
+    };
+    //if we stop waiting for any reason we leave the job, which stops once nobody else needs it
+    let _leave_on_exit = cancel.clone().drop_guard();
+
+    let button_id = format!("embed-cancel-{}", Uuid::new_v4());
+    status.start("Awaiting Download...", &button_id).await;
+    let mut persisted = PersistedJob::new();
+    persisted
+        .save(&job, status.resumable(), JobState::Queued)
+        .await;
+    tokio::spawn(watch_cancel_button(
+        ctx.clone(),
+        button_id,
+        job.requester,
+        cancel.clone(),
+    ));
+
+    let mut next = Some(latest); //catch up on where the job is at if someone else started it
+    let mut item = String::new(); //which item progress is for, links with several are done one at a time
+    loop {
+        let event = match next.take() {
+            Some(event) => event,
+            None => tokio::select! {
+                event = events.recv() => match event {
+                    Ok(event) => event,
+                    Err(RecvError::Lagged(_)) => continue, //only progress is dropped, the final event is always last
+                    Err(RecvError::Closed) => break,
+                },
+                //others may still want the job, so it wont send us a cancel of its own
+                _ = cancel.cancelled() => YtDlpEvent::Cancelled,
+            },
+        };
+        match event {
+            YtDlpEvent::Queued {
+                position,
+                estimated_wait,
+            } => {
+                let mut content = format!("Awaiting Download... #{position} in queue");
+                if let Some(wait) = estimated_wait {
+                    content += &format!(", about {}", format_duration(wait));
+                }
+                status.update(content).await;
+            }
+            YtDlpEvent::DLStarted {
+                id,
+                extractor,
+                item: index,
+                items,
+                ..
+            } => {
+                // the .. ignores any remaining fields that we dont care for
+                item = item_label(index, items);
+                //items of a playlist are never cached on their own, a hit would post just that one
+                if index.is_none()
+                    && let Some(extractor) = extractor
+                {
+                    cache.identify(&extractor, &id);
+                    //embedded before through a different link, leaving the job stops it if nobody else needs it
+                    if let Some(cached) = cache.find_media(&policy).await {
+                        post_cached(ctx, &job, &cached, captions).await;
+                        status.clear().await;
+                        return Ok(EmbedOutcome::Posted);
+                    }
+                }
+                status.update(format!("Downloading{item}...")).await;
+                persisted
+                    .save(&job, status.resumable(), JobState::Running)
+                    .await;
+            }
+            YtDlpEvent::DLProgress { percent, .. } => {
+                status
+                    .update(format!("Downloading{item}... {percent}"))
+                    .await;
+            }
+            YtDlpEvent::PPStarted {
+                item: index, items, ..
+            } => {
+                item = item_label(index, items);
+                status.update(format!("Processing{item}...")).await;
+            }
+            YtDlpEvent::PPProgress { percent, .. } => {
+                status
+                    .update(format!("Processing{item}... {percent}"))
+                    .await;
+            }
+            YtDlpEvent::Completed {
+                media,
+                info,
+                outputs,
+                missing,
+            } => {
+                //we may have joined the job after it started downloading
+                if let Some((extractor, id)) = &media {
+                    cache.identify(extractor, id);
+                }
+                let posted = match outputs.as_slice() {
+                    [output] if missing == 0 => {
+                        let output = output.path();
+                        post_file(ctx, &embedder_data, output, &job, &cache, info, captions).await
+                    }
+                    _ => {
+                        let caption = caption(info.as_ref(), captions);
+                        post_files(ctx, &embedder_data, &outputs, missing, &job, caption).await
+                    }
+                };
+                drop(outputs); //the files are removed once every requester sharing them is done
+                status.clear().await;
+                return posted;
+            }
+            YtDlpEvent::Cancelled if !cancel.is_cancelled() => {
+                //nobody here cancelled it, the queue is shutting down so keep the row for after the restart
+                persisted.keep();
+                status
+                    .finish("The bot is restarting, this embed will be retried once it's back")
+                    .await;
+                return Ok(EmbedOutcome::Interrupted);
+            }
+            YtDlpEvent::Cancelled => {
+                status.finish("Download cancelled").await;
+                return Ok(EmbedOutcome::Cancelled);
+            }
+            YtDlpEvent::Failed { error } => {
+                status.clear().await;
+                bail_to_user!("Failed to embed [[link]](<{}>): {error}", job.url);
+            }
+            _ => { /*discard other events*/ }
+        }
+    }
+
+    //the job was dropped without a result, which shouldnt happen while we still hold a subscription
+    status.clear().await;
+    bail_to_user!(
+        "The download of [[link]](<{}>) stopped unexpectedly",
+        job.url
+    );
+}
+
+/// The job's quality and container, using the guild's defaults for whatever the requester left out.
+async fn video_format(job: &EmbedJob) -> Result<VideoFormat> {
+    if let OutputMode::Audio(_) = job.mode {
+        return Ok(VideoFormat::default()); //so audio requests share jobs and cache entries regardless
+    }
+    let (requester, guild_id) = (job.requester, job.guild_id);
+    let Quality(quality) = config::QUALITY_PREF
+        .resolve(job.quality.map(Quality), requester, guild_id)
+        .await?;
+    //nobody else sees a dm, so theres nobody to hold back
+    let allow_webm = match guild_id {
+        Some(guild_id) => config::EMBED_ALLOW_WEBM.get(guild_id).await?,
+        None => true,
+    };
+
+    let container = match job.container {
+        Some(VideoContainer::Webm) if !allow_webm => bail_to_user!(
+            "This server doesn't allow webm embeds, a moderator can turn them on with `/settings set embed_allow_webm true`"
+        ),
+        Some(container) => container,
+        //a preference for webm just doesn't apply where it isn't allowed
+        None => match config::CONTAINER_PREF
+            .resolve(None, requester, guild_id)
+            .await?
+        {
+            VideoContainer::Webm if !allow_webm => VideoContainer::Mp4,
+            container => container,
+        },
+    };
+    Ok(VideoFormat {
+        max_height: quality,
+        container,
+    })
+}
+
+/// Attaches the file, or links it from storage when it's over the upload limit.
+/// The info is cached along with it even when captions are off, another guild may want them.
+async fn post_file(
+    ctx: &SerenityContext,
+    embedder_data: &Mutex<EmbedderData>,
+    path: &Path,
+    job: &EmbedJob,
+    cache: &CacheLookup,
+    info: Option<MediaInfo>,
+    captions: bool,
+) -> Result<EmbedOutcome> {
+    let EmbedJob {
+        url,
+        sent_by,
+        channel_id,
+        byte_limit,
+        ..
+    } = job;
+    let caption = caption(info.as_ref(), captions);
+    let file_size = fs::metadata(path).await?.len();
+    let storage = embedder_data.lock().await.storage.clone();
+    if file_size > *byte_limit {
+        let uploaded = match &storage {
+            Some(storage) => storage
+                .upload(path)
+                .await
+                .inspect_err(|err| error!("Failed to upload {}: {err:#}", path.display()))
+                .ok()
+                .map(|uploaded| (storage, uploaded)),
+            None => None,
+        };
+
+        if let Some((storage, uploaded)) = uploaded {
+            //not wrapped in <> so discord embeds the uploaded file
+            let reply = job.result_message(
+                caption.as_deref(),
+                format!(
+                    "-# sent by: {sent_by} - [[link]](<{url}>) - [[file]]({})",
+                    uploaded.url
+                ),
+            );
+            channel_id.send_message(&ctx.http, reply).await.ok();
+            let entry = CachedEmbed::uploaded(&uploaded, file_size, storage, info);
+            cache.store(entry, Some(storage)).await;
+            return Ok(EmbedOutcome::Posted);
+        }
+
+        //dont use <> to allow it to embed if provider supports it, as we failed to
+        let reply = job.result_message(None, format!("-# sent by: {sent_by} - [[link]]({url})"));
+        channel_id.send_message(&ctx.http, reply).await.ok();
+
+        bail_to_user!(
+            "File for [[link]](<{url}>) too large to embed, server limit is {}, file size is {}, sent link instead",
+            format_bytes(*byte_limit),
+            format_bytes(file_size)
+        );
+    }
+
+    let attachment = CreateAttachment::path(path).await?; //can fail to open the file, but not likely
+    let message = job
+        .result_message(
+            caption.as_deref(),
+            format!("-# sent by: {sent_by} - [[link]](<{url}>)"),
+        )
+        .add_file(attachment);
+
+    //theres nothing we can do if it fails to send, and we want to make sure to delete the file afterwards
+    if let Ok(sent) = channel_id.send_message(&ctx.http, message).await
+        && let Some(attachment) = sent.attachments.first()
+    {
+        let entry = CachedEmbed::attachment(attachment.url.to_string(), file_size, info);
+        cache.store(entry, storage.as_deref()).await;
+    }
+    Ok(EmbedOutcome::Posted)
+}
+
+/// Posts the items of a link with several, packed into as few messages as the attachment and upload limits allow.
+/// Items over the upload limit are linked from storage instead, or left out when there is none.
+async fn post_files(
+    ctx: &SerenityContext,
+    embedder_data: &Mutex<EmbedderData>,
+    outputs: &[Arc<OutputFile>],
+    missing: usize,
+    job: &EmbedJob,
+    caption: Option<String>,
+) -> Result<EmbedOutcome> {
+    let EmbedJob {
+        url,
+        sent_by,
+        channel_id,
+        byte_limit,
+        ..
+    } = job;
+    let storage = embedder_data.lock().await.storage.clone();
+
+    let mut attachments = Vec::new();
+    let mut links = Vec::new();
+    let mut left_out = missing;
+    for output in outputs {
+        let path = output.path();
+        let size = fs::metadata(path).await?.len();
+        if size <= *byte_limit {
+            attachments.push((path, size));
+            continue;
+        }
+        let uploaded = match &storage {
+            Some(storage) => storage
+                .upload(path)
+                .await
+                .inspect_err(|err| error!("Failed to upload {}: {err:#}", path.display()))
+                .ok(),
+            None => None,
+        };
+        match uploaded {
+            Some(uploaded) => links.push(uploaded.url),
+            None => left_out += 1,
+        }
+    }
+
+    if attachments.is_empty() && links.is_empty() {
+        //dont use <> to allow it to embed if provider supports it, as we failed to
+        let reply = job.result_message(None, format!("-# sent by: {sent_by} - [[link]]({url})"));
+        channel_id.send_message(&ctx.http, reply).await.ok();
+        bail_to_user!(
+            "Nothing in [[link]](<{url}>) could be embedded under the server limit of {}, sent link instead",
+            format_bytes(*byte_limit)
+        );
+    }
+
+    let mut content = format!("-# sent by: {sent_by} - [[link]](<{url}>)");
+    for (index, link) in links.iter().enumerate() {
+        //not wrapped in <> so discord embeds the uploaded files
+        content += &format!(" - [[file {}]]({link})", index + 1);
+    }
+    if left_out > 0 {
+        content += &format!(
+            " - {left_out} of {} items couldn't be embedded",
+            outputs.len() + missing
+        );
+    }
+
+    //in order rather than tightest fit, galleries and threads read top to bottom
+    let mut batches: Vec<Vec<&Path>> = Vec::new();
+    let mut batch_size = 0;
+    for (path, size) in attachments {
+        match batches.last_mut() {
+            Some(batch) if batch.len() < MAX_ATTACHMENTS && batch_size + size <= *byte_limit => {
+                batch.push(path);
+                batch_size += size;
+            }
+            _ => {
+                batches.push(vec![path]);
+                batch_size = size;
+            }
+        }
+    }
+
+    let mut message = Some(job.result_message(caption.as_deref(), content)); //only the first message gets the header and the reply
+    for batch in batches {
+        let mut next = message.take().unwrap_or_else(CreateMessage::new);
+        for path in batch {
+            next = next.add_file(CreateAttachment::path(path).await?);
+        }
+        channel_id.send_message(&ctx.http, next).await.ok();
+    }
+    if let Some(message) = message {
+        channel_id.send_message(&ctx.http, message).await.ok(); //every item was linked
+    }
+    Ok(EmbedOutcome::Posted)
+}
+
+/// Progress label for one item of a link with several, empty for a single video.
+fn item_label(item: Option<usize>, items: Option<usize>) -> String {
+    match (item, items) {
+        (Some(item), Some(items)) => format!(" item {item}/{items}"),
+        (Some(item), None) => format!(" item {item}"),
+        _ => String::new(),
+    }
+}
+
+/// The caption to post above the media, if the guild wants one and yt-dlp told us anything worth showing.
+fn caption(info: Option<&MediaInfo>, captions: bool) -> Option<String> {
+    info.filter(|_| captions).and_then(MediaInfo::caption)
+}
+
+/// Links a copy we posted before, discord embeds it the same way as a fresh upload.
+async fn post_cached(ctx: &SerenityContext, job: &EmbedJob, cached: &CachedEmbed, captions: bool) {
+    let EmbedJob {
+        url,
+        sent_by,
+        channel_id,
+        ..
+    } = job;
+    let caption = caption(cached.info.as_ref(), captions);
+    let reply = job.result_message(
+        caption.as_deref(),
+        format!(
+            "-# sent by: {sent_by} - [[link]](<{url}>) - [[file]]({})",
+            cached.url
+        ),
+    );
+    channel_id.send_message(&ctx.http, reply).await.ok();
+}
+
+fn cancel_button(custom_id: &str) -> CreateActionRow<'static> {
+    CreateActionRow::Buttons(
+        vec![
+            CreateButton::new(custom_id.to_string())
+                .label("Cancel")
+                .style(ButtonStyle::Danger),
+        ]
+        .into(),
+    )
+}
+
+/// Cancels the job when its Cancel button is pressed by the requester or a moderator.
+/// Exits once the job's token is cancelled, which also happens when the job's caller stops waiting.
+async fn watch_cancel_button(
+    ctx: SerenityContext,
+    button_id: String,
+    requester: UserId,
+    cancel: CancellationToken,
+) {
+    let filter_id = button_id.clone();
+    let mut presses = ComponentInteractionCollector::new(&ctx)
+        .filter(move |press| press.data.custom_id.as_str() == filter_id)
+        .stream();
+
+    loop {
+        let press = tokio::select! {
+            press = presses.next() => match press {
+                Some(press) => press,
+                None => break,
+            },
+            _ = cancel.cancelled() => break,
+        };
+
+        let allowed = press.user.id == requester
+            || press
+                .member
+                .as_ref()
+                .and_then(|member| member.permissions)
+                .is_some_and(|permissions| permissions.manage_messages());
+
+        let response = if allowed {
+            cancel.cancel();
+            CreateInteractionResponse::UpdateMessage(
+                CreateInteractionResponseMessage::new()
+                    .content("Cancelling...")
+                    .components(vec![]),
+            )
+        } else {
+            CreateInteractionResponse::Message(
+                CreateInteractionResponseMessage::new()
+                    .content("Only the requester or a moderator can cancel this embed")
+                    .ephemeral(true),
+            )
+        };
+
+        if let Err(err) = press.create_response(&ctx.http, response).await {
+            warn!("Failed to respond to cancel button {button_id}: {err}");
+        }
+    }
+}
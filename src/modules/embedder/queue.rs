@@ -0,0 +1,877 @@
+use crate::modules::embedder::{download, model::*, url_policy::HostRules};
+use crate::prelude::*;
+use futures::FutureExt;
+use std::{
+    collections::{HashMap, VecDeque},
+    panic::AssertUnwindSafe,
+    sync::{
+        Mutex as StdMutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, Instant},
+};
+use thiserror::Error;
+use tokio::{
+    sync::{Notify, Semaphore, broadcast},
+    task::JoinHandle,
+};
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
+
+const DURATION_SAMPLES: usize = 20; //how many finished jobs the rolling average covers
+
+#[derive(Debug, Error)]
+pub enum QueueError {
+    #[error("the download queue is full")]
+    Full,
+    #[error("the download queue is shutting down")]
+    Closed,
+    #[error("you already have {queued} embeds queued")]
+    UserLimit { queued: usize },
+}
+
+/// Queue of downloads that, unlike a channel, can be inspected while jobs wait in it.
+/// Slots are shared fairly between guilds and then between the users in each guild, by their weights, rather than
+/// first come first served.
+/// Requests for the same link with the same options share a single job while it's in flight.
+pub struct DownloadQueue {
+    state: Arc<QueueState>,
+    handle: Mutex<Option<JoinHandle<()>>>,
+    cancel: CancellationToken,
+}
+
+struct QueueState {
+    //locked in the order they're declared when more than one is needed
+    jobs: StdMutex<HashMap<JobKey, Arc<SharedJob>>>,
+    pending: StdMutex<VecDeque<QueuedJob>>,
+    running: StdMutex<Usage>,
+    durations: StdMutex<VecDeque<Duration>>,
+    notify: Notify,
+    /// Woken whenever a running job finishes, for [`DownloadQueue::drain`].
+    finished: Notify,
+    /// Set once the bot starts shutting down, no new jobs are accepted or started after that.
+    closed: AtomicBool,
+    capacity: usize,
+    concurrency: usize,
+    user_limit: usize,
+    guild_limit: Option<usize>,
+    shares: Shares,
+}
+
+/// The weights [`fair_order`] gives each guild and user.
+#[derive(Default)]
+struct Shares {
+    guilds: Weights,
+    users: Weights,
+}
+
+impl Shares {
+    fn guild(&self, guild_id: Option<GuildId>) -> u32 {
+        guild_id.map_or(1, |guild_id| self.guilds.get(guild_id.get()))
+    }
+
+    fn user(&self, user_id: UserId) -> u32 {
+        self.users.get(user_id.get())
+    }
+}
+
+/// How many jobs each guild and user has running, what the fair ordering and the caps are based on.
+#[derive(Default)]
+struct Usage {
+    guilds: HashMap<Option<GuildId>, usize>,
+    users: HashMap<UserId, usize>,
+}
+
+impl Usage {
+    fn guild(&self, guild_id: Option<GuildId>) -> usize {
+        self.guilds.get(&guild_id).copied().unwrap_or(0)
+    }
+
+    fn user(&self, user_id: UserId) -> usize {
+        self.users.get(&user_id).copied().unwrap_or(0)
+    }
+
+    fn start(&mut self, owner: JobOwner) {
+        *self.guilds.entry(owner.guild_id).or_default() += 1;
+        *self.users.entry(owner.user_id).or_default() += 1;
+    }
+
+    fn is_empty(&self) -> bool {
+        self.users.is_empty()
+    }
+
+    fn finish(&mut self, owner: JobOwner) {
+        //entries are dropped at zero so the maps only hold whoever is running something
+        if let Some(count) = self.guilds.get_mut(&owner.guild_id) {
+            *count -= 1;
+            if *count == 0 {
+                self.guilds.remove(&owner.guild_id);
+            }
+        }
+        if let Some(count) = self.users.get_mut(&owner.user_id) {
+            *count -= 1;
+            if *count == 0 {
+                self.users.remove(&owner.user_id);
+            }
+        }
+    }
+}
+
+struct QueuedJob {
+    key: JobKey,
+    shared: Arc<SharedJob>,
+    request: DownloadRequest,
+}
+
+/// Everything that changes what a job produces, two requests with the same key get the same file.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct JobKey {
+    url: String,
+    mode: OutputMode,
+    format: VideoFormat,
+    clip: Option<ClipRange>,
+    byte_limit: u64,
+    /// Where yt-dlp ends up is only checked against the first requester's rules, so only guilds with the
+    /// same rules can share a job.
+    guild_rules: HostRules,
+}
+
+impl JobKey {
+    fn new(request: &DownloadRequest) -> Self {
+        Self {
+            url: normalize_url(&request.url),
+            mode: request.mode,
+            format: request.format,
+            clip: request.clip,
+            byte_limit: request.byte_limit,
+            guild_rules: request.policy.guild_rules(),
+        }
+    }
+}
+
+/// A job and the requests waiting on it.
+struct SharedJob {
+    id: Uuid,
+    events: JobEvents,
+    /// Cancels the job itself, which happens once every consumer has left or the queue shuts down.
+    cancel: CancellationToken,
+    consumers: StdMutex<usize>,
+}
+
+/// A single request's view of a job.
+pub struct Subscription {
+    pub id: Uuid,
+    /// Where the job was at when we joined, [`YtDlpEvent::Unknown`] for a brand new job.
+    pub latest: YtDlpEvent,
+    pub events: broadcast::Receiver<YtDlpEvent>,
+    /// Cancelling this leaves the job, which only stops once nobody else is waiting on it either.
+    pub cancel: CancellationToken,
+}
+
+impl SharedJob {
+    fn new(cancel: CancellationToken) -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            events: JobEvents::new(),
+            cancel,
+            consumers: StdMutex::new(0),
+        }
+    }
+
+    /// Adds a consumer, `None` if the job was already cancelled and can't be joined anymore.
+    fn subscribe(self: &Arc<Self>) -> Option<Subscription> {
+        {
+            let mut consumers = self.consumers.lock().expect("job lock poisoned");
+            if self.cancel.is_cancelled() {
+                return None;
+            }
+            *consumers += 1;
+        }
+
+        let (latest, events) = self.events.subscribe();
+        let cancel = CancellationToken::new();
+
+        let job = self.clone();
+        let leave = cancel.clone();
+        tokio::spawn(async move {
+            leave.cancelled().await;
+            let mut consumers = job.consumers.lock().expect("job lock poisoned");
+            *consumers -= 1;
+            //consumers leave once theyre done with a finished job as well, that mustnt count as a cancel
+            if *consumers == 0 && !job.events.is_finished() {
+                job.cancel.cancel();
+            }
+        });
+
+        Some(Subscription {
+            id: self.id,
+            latest,
+            events,
+            cancel,
+        })
+    }
+}
+
+impl DownloadQueue {
+    pub fn new() -> Self {
+        let concurrency = EMBEDDER_CONCURRENCY_LIMIT.get().clone();
+        let state = Arc::new(QueueState {
+            jobs: StdMutex::new(HashMap::new()),
+            pending: StdMutex::new(VecDeque::new()),
+            running: StdMutex::new(Usage::default()),
+            durations: StdMutex::new(VecDeque::with_capacity(DURATION_SAMPLES)),
+            notify: Notify::new(),
+            finished: Notify::new(),
+            closed: AtomicBool::new(false),
+            capacity: EMBEDDER_MAX_QUEUE
+                .get()
+                .clone()
+                .unwrap_or(Semaphore::MAX_PERMITS),
+            concurrency,
+            user_limit: EMBEDDER_USER_MAX_JOBS
+                .get()
+                .unwrap_or(DEFAULT_USER_MAX_JOBS),
+            guild_limit: *EMBEDDER_GUILD_CONCURRENCY_LIMIT.get(),
+            shares: Shares {
+                guilds: EMBEDDER_GUILD_WEIGHTS.get().clone().unwrap_or_default(),
+                users: EMBEDDER_USER_WEIGHTS.get().clone().unwrap_or_default(),
+            },
+        });
+
+        let cancel = CancellationToken::new();
+        let handle = tokio::spawn(dispatch(state.clone(), cancel.clone()));
+
+        Self {
+            state,
+            handle: Mutex::new(Some(handle)),
+            cancel,
+        }
+    }
+
+    /// Joins the job already in flight for the same link and options, otherwise queues a new one.
+    /// Joining is free, only new jobs count towards the owner's limit.
+    /// The job is removed from the queue again, or stopped, once every subscription to it is cancelled.
+    pub fn try_enqueue(&self, request: DownloadRequest) -> Result<Subscription, QueueError> {
+        if self.cancel.is_cancelled() || self.state.closed.load(Ordering::Relaxed) {
+            return Err(QueueError::Closed);
+        }
+
+        let key = JobKey::new(&request);
+        let mut jobs = self.state.jobs.lock().expect("queue lock poisoned");
+        //the link was checked against this requester's rules before it got here, so joining is safe
+        if let Some(job) = jobs.get(&key)
+            && let Some(subscription) = job.subscribe()
+        {
+            debug!("Joining download {} for {}", job.id, key.url);
+            return Ok(subscription);
+        }
+
+        let job = Arc::new(SharedJob::new(self.cancel.child_token()));
+        {
+            let mut pending = self.state.pending.lock().expect("queue lock poisoned");
+            if pending.len() >= self.state.capacity {
+                return Err(QueueError::Full);
+            }
+            self.state
+                .check_user_limit(&pending, request.owner.user_id)?;
+            pending.push_back(QueuedJob {
+                key: key.clone(),
+                shared: job.clone(),
+                request,
+            });
+        }
+        jobs.insert(key.clone(), job.clone());
+        //only fails if the queue shut down since we checked, the job is cancelled with it
+        let subscription = job.subscribe().ok_or(QueueError::Closed)?;
+        drop(jobs);
+
+        let state = self.state.clone();
+        tokio::spawn(async move {
+            job.cancel.cancelled().await;
+            if state.remove(job.id).is_some() {
+                job.events.send(YtDlpEvent::Cancelled);
+                state.forget(&key, &job);
+                state.broadcast_positions();
+            }
+        });
+
+        self.state.broadcast_positions();
+        self.state.notify.notify_one();
+        Ok(subscription)
+    }
+
+    /// Stops accepting and starting jobs, then waits for the running ones to finish.
+    /// Jobs that were still waiting stay queued until [`Self::shutdown`] cancels them.
+    pub async fn drain(&self) {
+        self.state.closed.store(true, Ordering::Relaxed);
+        loop {
+            //created before checking so a job finishing in between still wakes us
+            let finished = self.state.finished.notified();
+            if self
+                .state
+                .running
+                .lock()
+                .expect("queue lock poisoned")
+                .is_empty()
+            {
+                return;
+            }
+            finished.await;
+        }
+    }
+
+    /// Cancels every job, running or not, and waits for their processes to be killed.
+    pub async fn shutdown(&self) {
+        self.cancel.cancel();
+        if let Some(handle) = self.handle.lock().await.take() {
+            let _ = handle.await; //wait for any tasks to finish
+        }
+    }
+}
+
+impl QueueState {
+    /// Waits for and pops the next job that is allowed to start, marking it as running.
+    async fn next(&self) -> QueuedJob {
+        loop {
+            if let Some(job) = self.take_next() {
+                return job;
+            }
+            //woken by new jobs and by finished ones, which can free up a capped guild
+            self.notify.notified().await;
+        }
+    }
+
+    /// Fails when the user already has as many jobs waiting or running as they're allowed.
+    fn check_user_limit(
+        &self,
+        pending: &VecDeque<QueuedJob>,
+        user_id: UserId,
+    ) -> Result<(), QueueError> {
+        let queued = pending
+            .iter()
+            .filter(|job| job.request.owner.user_id == user_id)
+            .count()
+            + self
+                .running
+                .lock()
+                .expect("queue lock poisoned")
+                .user(user_id);
+        if queued >= self.user_limit {
+            return Err(QueueError::UserLimit { queued });
+        }
+        Ok(())
+    }
+
+    fn take_next(&self) -> Option<QueuedJob> {
+        if self.closed.load(Ordering::Relaxed) {
+            return None;
+        }
+        let mut pending = self.pending.lock().expect("queue lock poisoned");
+        let mut running = self.running.lock().expect("queue lock poisoned");
+        let index = fair_order(&pending, &running, &self.shares)
+            .into_iter()
+            .find(|&index| {
+                let guild_id = pending[index].request.owner.guild_id;
+                self.guild_limit
+                    .is_none_or(|limit| running.guild(guild_id) < limit)
+            })?;
+        let job = pending.remove(index)?;
+        running.start(job.request.owner);
+        Some(job)
+    }
+
+    /// Frees the slot of a job that was taken by [`Self::next`].
+    fn finish(&self, owner: JobOwner) {
+        self.running
+            .lock()
+            .expect("queue lock poisoned")
+            .finish(owner);
+        self.notify.notify_one();
+        self.finished.notify_waiters();
+        self.broadcast_positions(); //positions depend on what everyone has running
+    }
+
+    fn remove(&self, id: Uuid) -> Option<QueuedJob> {
+        let mut pending = self.pending.lock().expect("queue lock poisoned");
+        let index = pending.iter().position(|job| job.shared.id == id)?;
+        pending.remove(index)
+    }
+
+    /// Stops new requests from joining `job`, leaving alone any newer job that has since taken its key.
+    fn forget(&self, key: &JobKey, job: &Arc<SharedJob>) {
+        let mut jobs = self.jobs.lock().expect("queue lock poisoned");
+        if jobs
+            .get(key)
+            .is_some_and(|current| Arc::ptr_eq(current, job))
+        {
+            jobs.remove(key);
+        }
+    }
+
+    fn record_duration(&self, duration: Duration) {
+        let mut durations = self.durations.lock().expect("queue lock poisoned");
+        if durations.len() == DURATION_SAMPLES {
+            durations.pop_front();
+        }
+        durations.push_back(duration);
+    }
+
+    fn average_duration(&self) -> Option<Duration> {
+        let durations = self.durations.lock().expect("queue lock poisoned");
+        let count = u32::try_from(durations.len()).ok().filter(|&count| count > 0)?;
+        Some(durations.iter().sum::<Duration>() / count)
+    }
+
+    /// Tells every waiting job where it now sits in the queue.
+    fn broadcast_positions(&self) {
+        let average = self.average_duration();
+        let pending = self.pending.lock().expect("queue lock poisoned");
+        let running = self.running.lock().expect("queue lock poisoned");
+        let order = fair_order(&pending, &running, &self.shares);
+        drop(running);
+        for (index, job) in order.into_iter().map(|index| &pending[index]).enumerate() {
+            let position = index + 1;
+            //jobs ahead of us drain `concurrency` at a time, each taking about `average`
+            let estimated_wait = average.map(|average| {
+                average * u32::try_from(position.div_ceil(self.concurrency)).unwrap_or(u32::MAX)
+            });
+            job.shared.events.send(YtDlpEvent::Queued {
+                position,
+                estimated_wait,
+            });
+        }
+    }
+}
+
+async fn dispatch(state: Arc<QueueState>, cancel: CancellationToken) {
+    let permits = Arc::new(Semaphore::new(state.concurrency));
+
+    loop {
+        let permit = tokio::select! {
+            permit = permits.clone().acquire_owned() => permit.expect("semaphore is never closed"),
+            _ = cancel.cancelled() => break,
+        };
+
+        let job = tokio::select! {
+            job = state.next() => job,
+            _ = cancel.cancelled() => break,
+        };
+        state.broadcast_positions();
+
+        let state = state.clone();
+        tokio::spawn(async move {
+            let QueuedJob {
+                key,
+                shared,
+                request,
+            } = job;
+            let owner = request.owner;
+            if shared.cancel.is_cancelled() {
+                //cancelled between being popped and started
+                shared.events.send(YtDlpEvent::Cancelled);
+                state.forget(&key, &shared);
+                state.finish(owner);
+                return;
+            }
+
+            let started = Instant::now();
+            let download = AssertUnwindSafe(download(request, &shared.events, &shared.cancel));
+            if download.catch_unwind().await.is_err() {
+                //consumers hold on to the job, so a dead task wouldnt close their stream for them
+                error!("Download task for job {} panicked", shared.id);
+                shared.events.send(YtDlpEvent::Failed {
+                    error: YtDlpError::Unknown { code: None },
+                });
+            }
+            if !shared.cancel.is_cancelled() {
+                state.record_duration(started.elapsed()); //cancelled jobs would drag the average down
+            }
+            //consumers that already joined still get the file, new requests start over
+            state.forget(&key, &shared);
+            shared.cancel.cancel(); //nothing is left to stop, this just lets the queue watcher exit
+            state.finish(owner);
+            drop(permit);
+        });
+    }
+
+    //job tokens are children of ours so running jobs are already being killed, wait for them to clean up
+    let _ = permits
+        .acquire_many(u32::try_from(state.concurrency).unwrap_or(u32::MAX))
+        .await;
+}
+
+/// Indices into `pending` in the order the jobs should start.
+/// Each guild takes turns, and the users in a guild take turns the same way within its share. A guild or user
+/// with a weight of n gets n turns for every one a guild or user with a weight of 1 gets. Jobs that are already
+/// running count as earlier turns, so whoever is using the most of their share goes last. Ties go to whichever job
+/// was queued first.
+fn fair_order(pending: &VecDeque<QueuedJob>, running: &Usage, shares: &Shares) -> Vec<usize> {
+    let mut user_turns = HashMap::new();
+    let mut guilds = HashMap::<_, Vec<_>>::new();
+    for (index, job) in pending.iter().enumerate() {
+        let JobOwner { user_id, guild_id } = job.request.owner;
+        let nth = user_turns
+            .entry(user_id)
+            .or_insert_with(|| running.user(user_id));
+        let turn = turn_at(*nth, shares.user(user_id));
+        guilds.entry(guild_id).or_default().push((turn, index));
+        *nth += 1;
+    }
+
+    let mut order = Vec::with_capacity(pending.len());
+    for (guild_id, mut jobs) in guilds {
+        jobs.sort_unstable_by(by_turn);
+        let (running, weight) = (running.guild(guild_id), shares.guild(guild_id));
+        order.extend(
+            jobs.into_iter()
+                .enumerate()
+                .map(|(nth, (_, index))| (turn_at(running + nth, weight), index)),
+        );
+    }
+    order.sort_unstable_by(by_turn);
+    order.into_iter().map(|(_, index)| index).collect()
+}
+
+/// When the `nth` turn of a guild or user with `weight` comes up, heavier weights have theirs closer together.
+fn turn_at(nth: usize, weight: u32) -> f64 {
+    (nth + 1) as f64 / f64::from(weight)
+}
+
+fn by_turn(a: &(f64, usize), b: &(f64, usize)) -> std::cmp::Ordering {
+    a.0.total_cmp(&b.0).then(a.1.cmp(&b.1))
+}
+
+/// Reduces a link to what decides the media behind it, so the usual variations of a share link map to one job.
+pub fn normalize_url(url: &Url) -> String {
+    let host = url.host_str().unwrap_or_default();
+    let host = host
+        .strip_prefix("www.")
+        .or_else(|| host.strip_prefix("m."))
+        .unwrap_or(host);
+    let mut path = url.path().trim_end_matches('/').to_string();
+    let mut query = url
+        .query_pairs()
+        .filter(|(key, _)| !is_tracking_param(key))
+        .map(|(key, value)| (key.into_owned(), value.into_owned()))
+        .collect::<Vec<_>>();
+
+    //short links and shorts are the same video as the watch page
+    let video_id = match host {
+        "youtu.be" => path.strip_prefix('/'),
+        "youtube.com" => path
+            .strip_prefix("/shorts/")
+            .or_else(|| path.strip_prefix("/live/")),
+        _ => None,
+    }
+    .filter(|id| !id.is_empty() && !id.contains('/'))
+    .map(ToString::to_string);
+    let host = match video_id {
+        Some(id) => {
+            path = "/watch".to_string();
+            query.push(("v".to_string(), id));
+            "youtube.com"
+        }
+        None => host,
+    };
+
+    query.sort();
+    let query = url::form_urlencoded::Serializer::new(String::new())
+        .extend_pairs(&query)
+        .finish();
+    let port = url
+        .port()
+        .map(|port| format!(":{port}"))
+        .unwrap_or_default();
+    format!("{host}{port}{path}?{query}")
+}
+
+/// Query parameters added by share buttons and ad networks, they never change the media.
+fn is_tracking_param(key: &str) -> bool {
+    key.starts_with("utm_")
+        || matches!(
+            key,
+            "si" | "feature" | "fbclid" | "gclid" | "igshid" | "igsh" | "ref_src" | "ref_url"
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::embedder::url_policy::{SystemResolver, UrlPolicy};
+
+    fn owner(user: u64, guild: Option<u64>) -> JobOwner {
+        JobOwner {
+            user_id: UserId::new(user),
+            guild_id: guild.map(GuildId::new),
+        }
+    }
+
+    fn job(user: u64, guild: Option<u64>) -> QueuedJob {
+        let request = DownloadRequest {
+            url: Url::parse(&format!("https://example.com/{user}")).unwrap(),
+            mode: OutputMode::Video,
+            format: VideoFormat::default(),
+            clip: None,
+            byte_limit: 8 * 1024 * 1024,
+            policy: UrlPolicy::new(
+                Arc::new(SystemResolver),
+                HostRules::default(),
+                HostRules::default(),
+            ),
+            owner: owner(user, guild),
+        };
+        QueuedJob {
+            key: JobKey::new(&request),
+            shared: Arc::new(SharedJob::new(CancellationToken::new())),
+            request,
+        }
+    }
+
+    fn pending(owners: &[(u64, Option<u64>)]) -> VecDeque<QueuedJob> {
+        owners
+            .iter()
+            .map(|&(user, guild)| job(user, guild))
+            .collect()
+    }
+
+    fn running(owners: &[(u64, Option<u64>)]) -> Usage {
+        let mut usage = Usage::default();
+        for &(user, guild) in owners {
+            usage.start(owner(user, guild));
+        }
+        usage
+    }
+
+    fn state(user_limit: usize, guild_limit: Option<usize>) -> QueueState {
+        QueueState {
+            jobs: StdMutex::new(HashMap::new()),
+            pending: StdMutex::new(VecDeque::new()),
+            running: StdMutex::new(Usage::default()),
+            durations: StdMutex::new(VecDeque::new()),
+            notify: Notify::new(),
+            finished: Notify::new(),
+            closed: AtomicBool::new(false),
+            capacity: 100,
+            concurrency: 4,
+            user_limit,
+            guild_limit,
+            shares: Shares::default(),
+        }
+    }
+
+    fn next_user(state: &QueueState) -> Option<u64> {
+        state.take_next().map(|job| job.request.owner.user_id.get())
+    }
+
+    #[test]
+    fn guilds_then_users_take_turns() {
+        let pending = pending(&[(1, Some(10)), (1, Some(10)), (2, Some(10)), (3, Some(20))]);
+        //the other guild's first job beats the first guild's second, which goes to the user who had none yet
+        assert_eq!(
+            fair_order(&pending, &Usage::default(), &Shares::default()),
+            [0, 3, 2, 1]
+        );
+
+        let dms = self::pending(&[(1, None), (1, None), (2, None)]);
+        assert_eq!(
+            fair_order(&dms, &Usage::default(), &Shares::default()),
+            [0, 2, 1]
+        );
+    }
+
+    #[test]
+    fn running_jobs_count_as_earlier_turns() {
+        let pending = pending(&[(1, Some(10)), (2, Some(20))]);
+        assert_eq!(
+            fair_order(&pending, &running(&[(9, Some(10))]), &Shares::default()),
+            [1, 0]
+        );
+
+        let pending = self::pending(&[(1, Some(10)), (2, Some(10))]);
+        assert_eq!(
+            fair_order(&pending, &running(&[(1, Some(10))]), &Shares::default()),
+            [1, 0]
+        );
+        //ties go to whoever queued first
+        assert_eq!(
+            fair_order(&pending, &Usage::default(), &Shares::default()),
+            [0, 1]
+        );
+    }
+
+    /// How many of the first `turns` in `order` went to each user.
+    fn turns_by_user(
+        pending: &VecDeque<QueuedJob>,
+        order: &[usize],
+        turns: usize,
+    ) -> HashMap<u64, usize> {
+        let mut counts = HashMap::new();
+        for &index in &order[..turns] {
+            *counts
+                .entry(pending[index].request.owner.user_id.get())
+                .or_default() += 1;
+        }
+        counts
+    }
+
+    #[test]
+    fn heavier_guilds_get_more_turns() {
+        //user 1 is alone in guild 10, user 2 in guild 20
+        let pending = pending(
+            &[(1, Some(10)); 12]
+                .into_iter()
+                .chain([(2, Some(20)); 12])
+                .collect::<Vec<_>>(),
+        );
+        let shares = Shares {
+            guilds: "10=3".parse().unwrap(),
+            users: Weights::default(),
+        };
+        let order = fair_order(&pending, &Usage::default(), &shares);
+        assert_eq!(
+            turns_by_user(&pending, &order, 8),
+            HashMap::from([(1, 6), (2, 2)])
+        );
+        assert_eq!(
+            turns_by_user(&pending, &order, 16),
+            HashMap::from([(1, 12), (2, 4)])
+        );
+
+        //running jobs use up a heavier guild's turns the same way
+        let order = fair_order(&pending, &running(&[(1, Some(10)); 3]), &shares);
+        assert_eq!(pending[order[0]].request.owner.user_id.get(), 2);
+    }
+
+    #[test]
+    fn heavier_users_get_more_turns_within_their_guild() {
+        let pending = pending(
+            &[(1, Some(10)); 8]
+                .into_iter()
+                .chain([(2, Some(10)); 8])
+                .collect::<Vec<_>>(),
+        );
+        let shares = Shares {
+            guilds: Weights::default(),
+            users: "2=2".parse().unwrap(),
+        };
+        let order = fair_order(&pending, &Usage::default(), &shares);
+        assert_eq!(
+            turns_by_user(&pending, &order, 6),
+            HashMap::from([(1, 2), (2, 4)])
+        );
+        assert_eq!(
+            turns_by_user(&pending, &order, 12),
+            HashMap::from([(1, 4), (2, 8)])
+        );
+    }
+
+    #[test]
+    fn guild_limit_skips_a_blocked_head() {
+        let state = state(10, Some(1));
+        *state.running.lock().unwrap() = running(&[(9, Some(10))]);
+        *state.pending.lock().unwrap() = pending(&[(1, Some(10)), (2, Some(10))]);
+        assert_eq!(next_user(&state), None, "the guild is at its limit");
+
+        state.pending.lock().unwrap().push_back(job(3, Some(20)));
+        assert_eq!(next_user(&state), Some(3));
+        assert_eq!(next_user(&state), None);
+
+        state.finish(owner(9, Some(10)));
+        assert_eq!(next_user(&state), Some(1));
+        assert_eq!(state.pending.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn no_guild_limit_takes_the_head() {
+        let state = state(10, None);
+        *state.running.lock().unwrap() = running(&[(9, Some(10)), (8, Some(10))]);
+        *state.pending.lock().unwrap() = pending(&[(1, Some(10)), (2, Some(10))]);
+        assert_eq!(next_user(&state), Some(1));
+        assert_eq!(next_user(&state), Some(2));
+        assert_eq!(next_user(&state), None);
+    }
+
+    #[test]
+    fn closed_queue_starts_nothing() {
+        let state = state(10, None);
+        *state.pending.lock().unwrap() = pending(&[(1, Some(10))]);
+        state.closed.store(true, Ordering::Relaxed);
+        assert_eq!(next_user(&state), None);
+    }
+
+    #[test]
+    fn user_limit_counts_waiting_and_running_jobs() {
+        let state = state(2, None);
+        let pending = pending(&[(1, Some(10)), (2, Some(10))]);
+        assert!(state.check_user_limit(&pending, UserId::new(1)).is_ok());
+
+        *state.running.lock().unwrap() = running(&[(1, Some(20))]);
+        assert!(matches!(
+            state.check_user_limit(&pending, UserId::new(1)),
+            Err(QueueError::UserLimit { queued: 2 })
+        ));
+        assert!(state.check_user_limit(&pending, UserId::new(2)).is_ok());
+        assert!(state.check_user_limit(&pending, UserId::new(3)).is_ok());
+    }
+
+    fn normalized(link: &str) -> String {
+        normalize_url(&Url::parse(link).unwrap())
+    }
+
+    #[test]
+    fn youtube_links_share_a_key() {
+        let watch = normalized("https://www.youtube.com/watch?v=dQw4w9WgXcQ");
+        for link in [
+            "https://youtu.be/dQw4w9WgXcQ",
+            "https://youtu.be/dQw4w9WgXcQ?si=abc123",
+            "https://m.youtube.com/watch?v=dQw4w9WgXcQ&feature=share",
+            "https://youtube.com/shorts/dQw4w9WgXcQ",
+            "https://www.youtube.com/live/dQw4w9WgXcQ/",
+            "http://youtube.com/watch?v=dQw4w9WgXcQ",
+        ] {
+            assert_eq!(normalized(link), watch, "{link}");
+        }
+        assert_ne!(
+            normalized("https://youtu.be/dQw4w9WgXcR"),
+            watch,
+            "ids are case sensitive"
+        );
+    }
+
+    #[test]
+    fn strips_tracking_params_only() {
+        assert_eq!(
+            normalized("https://example.com/video?id=5&utm_source=x&fbclid=y&gclid=z&igsh=w"),
+            normalized("https://example.com/video?id=5"),
+        );
+        //a timestamp or playlist changes what's downloaded
+        let plain = normalized("https://www.youtube.com/watch?v=dQw4w9WgXcQ");
+        let timed = normalized("https://youtu.be/dQw4w9WgXcQ?t=42&si=abc");
+        assert_ne!(timed, plain);
+        assert_eq!(
+            timed,
+            normalized("https://www.youtube.com/watch?t=42&v=dQw4w9WgXcQ")
+        );
+        assert_ne!(
+            normalized("https://www.youtube.com/watch?v=dQw4w9WgXcQ&list=PL123"),
+            plain
+        );
+    }
+
+    #[test]
+    fn ignores_scheme_and_host_case() {
+        assert_eq!(
+            normalized("HTTPS://Example.COM/Clip/"),
+            normalized("http://example.com/Clip")
+        );
+        assert_ne!(
+            normalized("https://example.com/Clip"),
+            normalized("https://example.com/clip"),
+            "paths are case sensitive"
+        );
+        assert_ne!(
+            normalized("https://example.com:8443/clip"),
+            normalized("https://example.com/clip")
+        );
+    }
+}
@@ -0,0 +1,386 @@
+//! Keeps a row for every embed that's waiting or running, so a restart doesn't silently drop them.
+//! Rows left over from the last run are queued again once the bot is ready, with a notice in the channel.
+use crate::{
+    core::{
+        database::{self, sql_id},
+        error::UserError,
+    },
+    modules::embedder::{
+        model::*,
+        pipeline::{self, EmbedJob, EmbedOutcome, StatusMessage},
+    },
+    prelude::*,
+};
+use poise::serenity_prelude::Context as SerenityContext;
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+use tokio::runtime::Handle;
+use turso::{Row, Value};
+use uuid::Uuid;
+
+register_migration!(
+    "embedder_jobs",
+    1,
+    "CREATE TABLE IF NOT EXISTS embedder_jobs (
+        id TEXT PRIMARY KEY,
+        url TEXT NOT NULL,
+        mode TEXT NOT NULL,
+        clip_start REAL,
+        clip_end REAL,
+        byte_limit INTEGER NOT NULL,
+        requester INTEGER NOT NULL,
+        guild_id INTEGER,
+        channel_id INTEGER NOT NULL,
+        sent_by TEXT NOT NULL,
+        reply_to INTEGER,
+        status_message INTEGER,
+        status_reply_to INTEGER,
+        state TEXT NOT NULL,
+        requested_at INTEGER NOT NULL
+    )"
+);
+//databases from before migrations may have these already
+register_migration!("embedder_jobs", 2, async |connection| {
+    let mut rows = connection
+        .query("PRAGMA table_info(embedder_jobs)", ())
+        .await?;
+    let mut columns = Vec::new();
+    while let Some(row) = rows.next().await? {
+        if let Value::Text(name) = row.get_value(1)? {
+            columns.push(name);
+        }
+    }
+    drop(rows);
+
+    for (name, column) in [
+        ("quality", "quality INTEGER"),
+        ("container", "container TEXT"),
+    ] {
+        if !columns.iter().any(|existing| existing == name) {
+            connection
+                .execute(
+                    &format!("ALTER TABLE embedder_jobs ADD COLUMN {column}"),
+                    (),
+                )
+                .await?;
+        }
+    }
+    Ok(())
+});
+register_event_listener!(resume_jobs);
+
+const MAX_RESUME_AGE: Duration = Duration::from_secs(60 * 60); //past this the conversation has usually moved on
+
+static RESUMED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JobState {
+    Queued,
+    /// yt-dlp had started, whatever it fetched is gone after a restart so the job starts over.
+    Running,
+}
+
+impl JobState {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Queued => "queued",
+            Self::Running => "running",
+        }
+    }
+}
+
+impl FromStr for JobState {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "queued" => Ok(Self::Queued),
+            "running" => Ok(Self::Running),
+            other => bail!("unknown job state {other}"),
+        }
+    }
+}
+
+/// A job's row, removed again once this is dropped unless [`Self::keep`] was called.
+pub struct PersistedJob {
+    id: Uuid,
+    keep: bool,
+}
+
+impl PersistedJob {
+    pub fn new() -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            keep: false,
+        }
+    }
+
+    /// Writes where the job is at, `status` is the status message and what it replies to.
+    /// Failures are only logged, the job itself carries on fine without its row.
+    pub async fn save(
+        &self,
+        job: &EmbedJob,
+        status: (Option<MessageId>, Option<MessageId>),
+        state: JobState,
+    ) {
+        if let Err(err) = self.write(job, status, state).await {
+            warn!("Failed to save embed job {}: {err:#}", self.id);
+        }
+    }
+
+    /// Leaves the row in place so the job is picked up again after the restart.
+    pub fn keep(&mut self) {
+        self.keep = true;
+    }
+
+    async fn write(
+        &self,
+        job: &EmbedJob,
+        (status_message, status_reply_to): (Option<MessageId>, Option<MessageId>),
+        state: JobState,
+    ) -> Result<()> {
+        let optional_id = |id: Option<u64>| id.map_or(Value::Null, |id| Value::Integer(sql_id(id)));
+        let reply_to = job
+            .reply_to
+            .as_ref()
+            .and_then(|reference| reference.message_id);
+
+        let connection = database::connection().await?;
+        connection
+            .execute(
+                "INSERT INTO embedder_jobs (id, url, mode, clip_start, clip_end, byte_limit, requester,
+                    guild_id, channel_id, sent_by, reply_to, status_message, status_reply_to, state, requested_at,
+                    quality, container)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17)
+                ON CONFLICT (id) DO UPDATE SET status_message = excluded.status_message,
+                    status_reply_to = excluded.status_reply_to, state = excluded.state",
+                [
+                    Value::Text(self.id.to_string()),
+                    Value::Text(job.url.to_string()),
+                    Value::Text(job.mode.as_str().to_string()),
+                    job.clip
+                        .map_or(Value::Null, |clip| Value::Real(clip.start.as_secs_f64())),
+                    job.clip
+                        .and_then(|clip| clip.end)
+                        .map_or(Value::Null, |end| Value::Real(end.as_secs_f64())),
+                    Value::Integer(i64::try_from(job.byte_limit).unwrap_or(i64::MAX)),
+                    Value::Integer(sql_id(job.requester.get())),
+                    optional_id(job.guild_id.map(GuildId::get)),
+                    Value::Integer(sql_id(job.channel_id.get())),
+                    Value::Text(job.sent_by.clone()),
+                    optional_id(reply_to.map(MessageId::get)),
+                    optional_id(status_message.map(MessageId::get)),
+                    optional_id(status_reply_to.map(MessageId::get)),
+                    Value::Text(state.as_str().to_string()),
+                    Value::Integer(i64::try_from(job.requested_at).unwrap_or(i64::MAX)),
+                    job.quality
+                        .map_or(Value::Null, |quality| Value::Integer(quality.into())),
+                    job.container.map_or(Value::Null, |container| {
+                        Value::Text(container.as_str().to_string())
+                    }),
+                ],
+            )
+            .await?;
+        Ok(())
+    }
+}
+
+impl Drop for PersistedJob {
+    fn drop(&mut self) {
+        if self.keep {
+            return;
+        }
+        let id = self.id;
+        //without a runtime we're going down hard, the row is retried like after a crash
+        if let Ok(handle) = Handle::try_current() {
+            handle.spawn(async move {
+                if let Err(err) = remove(id).await {
+                    warn!("Failed to remove embed job {id}: {err:#}");
+                }
+            });
+        }
+    }
+}
+
+async fn remove(id: Uuid) -> Result<()> {
+    database::connection()
+        .await?
+        .execute("DELETE FROM embedder_jobs WHERE id = ?1", [id.to_string()])
+        .await?;
+    Ok(())
+}
+
+/// A job left over from before the restart.
+struct Interrupted {
+    job: EmbedJob,
+    state: JobState,
+    status_message: Option<MessageId>,
+    status_reply_to: Option<MessageId>,
+}
+
+impl Interrupted {
+    fn from_row(row: &Row) -> Result<Self> {
+        let text = |index: usize| -> Result<String> {
+            match row.get_value(index)? {
+                Value::Text(text) => Ok(text),
+                other => bail!("unexpected job column {other:?}"),
+            }
+        };
+        let optional_text = |index: usize| -> Result<Option<String>> {
+            match row.get_value(index)? {
+                Value::Text(text) => Ok(Some(text)),
+                Value::Null => Ok(None),
+                other => bail!("unexpected job column {other:?}"),
+            }
+        };
+        let integer = |index: usize| -> Result<Option<u64>> {
+            match row.get_value(index)? {
+                Value::Integer(value) => Ok(Some(value.cast_unsigned())),
+                Value::Null => Ok(None),
+                other => bail!("unexpected job column {other:?}"),
+            }
+        };
+        let seconds = |index: usize| -> Result<Option<Duration>> {
+            match row.get_value(index)? {
+                Value::Real(value) => Ok(Duration::try_from_secs_f64(value).ok()),
+                Value::Integer(value) => Ok(Some(Duration::from_secs(value.cast_unsigned()))),
+                Value::Null => Ok(None),
+                other => bail!("unexpected job column {other:?}"),
+            }
+        };
+        let required = |index: usize| -> Result<u64> {
+            integer(index)?.ok_or_else(|| anyhow::anyhow!("job column {index} is empty"))
+        };
+
+        let channel_id = GenericChannelId::new(required(8)?);
+        let reference =
+            |id: Option<u64>| id.map(|id| MessageReference::from((channel_id, MessageId::new(id))));
+        let job = EmbedJob {
+            url: Url::parse(&text(1)?)?,
+            mode: text(2)?.parse()?,
+            quality: integer(15)?.and_then(|quality| u32::try_from(quality).ok()),
+            container: optional_text(16)?
+                .map(|container| container.parse())
+                .transpose()?,
+            clip: seconds(3)?.map(|start| ClipRange {
+                start,
+                end: seconds(4).ok().flatten(),
+            }),
+            byte_limit: required(5)?,
+            requester: UserId::new(required(6)?),
+            guild_id: integer(7)?.map(GuildId::new),
+            channel_id,
+            sent_by: text(9)?,
+            reply_to: reference(integer(10)?),
+            requested_at: required(14)?,
+        };
+
+        Ok(Self {
+            job,
+            state: text(13)?.parse()?,
+            status_message: integer(11)?.map(MessageId::new),
+            status_reply_to: integer(12)?.map(MessageId::new),
+        })
+    }
+}
+
+/// Loads and clears every job left over from the last run, running ones first as they were queued earliest.
+async fn take_all() -> Result<Vec<Interrupted>> {
+    let connection = database::connection().await?;
+    let mut rows = connection
+        .query(
+            "SELECT id, url, mode, clip_start, clip_end, byte_limit, requester, guild_id, channel_id,
+                sent_by, reply_to, status_message, status_reply_to, state, requested_at, quality, container
+            FROM embedder_jobs ORDER BY state = 'running' DESC, requested_at",
+            (),
+        )
+        .await?;
+
+    let mut jobs = Vec::new();
+    while let Some(row) = rows.next().await? {
+        match Interrupted::from_row(&row) {
+            Ok(job) => jobs.push(job),
+            Err(err) => warn!("Skipping unreadable embed job: {err:#}"),
+        }
+    }
+    drop(rows);
+
+    //resumed jobs get new rows, so the old ones go regardless of how the retry turns out
+    connection.execute("DELETE FROM embedder_jobs", ()).await?;
+    Ok(jobs)
+}
+
+async fn resume_jobs(
+    ctx: FrameworkContext<'_, GlobalState, Error>,
+    event: &FullEvent,
+) -> Result<()> {
+    let FullEvent::Ready { .. } = event else {
+        return Ok(());
+    };
+    //ready is sent again for every new gateway session, only the first one follows a restart
+    if RESUMED.swap(true, Ordering::Relaxed) {
+        return Ok(());
+    }
+
+    let jobs = take_all().await?;
+    if !jobs.is_empty() {
+        info!("Retrying {} embeds interrupted by the restart", jobs.len());
+    }
+    for job in jobs {
+        tokio::spawn(resume(ctx.serenity_context.clone(), job));
+    }
+    Ok(())
+}
+
+async fn resume(ctx: SerenityContext, interrupted: Interrupted) {
+    let Interrupted {
+        job,
+        state,
+        status_message,
+        status_reply_to,
+    } = interrupted;
+
+    //embeds requested through a command had an ephemeral status, that token is gone so a channel message replaces it
+    let message = match status_message {
+        Some(id) => job.channel_id.message(&ctx, id).await.ok(),
+        None => None,
+    };
+    let had_status = message.is_some();
+    let mut status = StatusMessage::Message {
+        ctx: &ctx,
+        channel_id: job.channel_id,
+        reply_to: status_reply_to.map(|id| MessageReference::from((job.channel_id, id))),
+        message,
+    };
+
+    if unix_now().saturating_sub(job.requested_at) > MAX_RESUME_AGE.as_secs() {
+        if had_status {
+            status
+                .finish("The bot restarted and this embed was too old to retry")
+                .await;
+            status.linger_and_clear().await;
+        }
+        return;
+    }
+
+    let notice = match state {
+        JobState::Queued => format!("Bot restarted, [[link]](<{}>) is queued again", job.url),
+        JobState::Running => format!("Bot restarted, retrying [[link]](<{}>)", job.url),
+    };
+    status.update(notice).await;
+
+    match pipeline::run(&ctx, job, &mut status).await {
+        Ok(EmbedOutcome::Posted | EmbedOutcome::Interrupted) => {}
+        Ok(EmbedOutcome::Cancelled) => status.linger_and_clear().await,
+        Err(err) if err.is::<UserError>() => {
+            status.finish(err.to_string()).await;
+            status.linger_and_clear().await;
+        }
+        Err(err) => {
+            error!("Failed to retry an embed after the restart: {err:#}");
+            status.clear().await;
+        }
+    }
+}
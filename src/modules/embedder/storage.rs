@@ -0,0 +1,203 @@
+use crate::prelude::*;
+use anyhow::Context;
+use opendal::{Operator, services};
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tokio::{fs::File, io::AsyncReadExt};
+use uuid::Uuid;
+
+//object storage for embeds that are too large to attach, S3 wins if both backends are configured
+register_env!(EMBEDDER_S3_BUCKET, Option<String>);
+register_env!(EMBEDDER_S3_ENDPOINT, Option<String>);
+register_env!(EMBEDDER_S3_REGION, Option<String>);
+register_env!(EMBEDDER_S3_ACCESS_KEY_ID, Option<String>);
+register_env!(EMBEDDER_S3_SECRET_ACCESS_KEY, Option<String>);
+register_env!(EMBEDDER_STORAGE_FS_ROOT, Option<PathBuf>);
+register_env!(EMBEDDER_STORAGE_PUBLIC_URL, Option<Url>);
+register_env!(EMBEDDER_STORAGE_RETENTION_HOURS, Option<u64>);
+
+register_startup_listener!(validate_storage);
+
+pub const DEFAULT_RETENTION_HOURS: u64 = 72;
+const OBJECT_PREFIX: &str = "embeds/";
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const UPLOAD_CHUNK_SIZE: usize = 8 * 1024 * 1024;
+const MAX_PRESIGN_EXPIRY: Duration = Duration::from_secs(7 * 24 * 60 * 60); //S3 refuses anything longer
+
+pub struct EmbedStorage {
+    operator: Operator,
+    public_url: Option<Url>,
+    retention: Duration,
+}
+
+/// An uploaded object and the url it can be reached at.
+pub struct Uploaded {
+    pub key: String,
+    pub url: Url,
+}
+
+impl EmbedStorage {
+    /// Builds the storage backend from the environment, returns `None` if no backend is configured.
+    pub fn from_env() -> Result<Option<Self>> {
+        let operator = if let Some(bucket) = EMBEDDER_S3_BUCKET.get() {
+            let mut builder = services::S3::default()
+                .bucket(bucket)
+                .region(EMBEDDER_S3_REGION.get().as_deref().unwrap_or("auto"));
+            if let Some(endpoint) = EMBEDDER_S3_ENDPOINT.get() {
+                builder = builder.endpoint(endpoint);
+            }
+            if let Some(key_id) = EMBEDDER_S3_ACCESS_KEY_ID.get() {
+                builder = builder.access_key_id(key_id);
+            }
+            if let Some(secret) = EMBEDDER_S3_SECRET_ACCESS_KEY.get() {
+                builder = builder.secret_access_key(secret);
+            }
+            Operator::new(builder)?.finish()
+        } else if let Some(root) = EMBEDDER_STORAGE_FS_ROOT.get() {
+            Operator::new(services::Fs::default().root(&root.to_string_lossy()))?.finish()
+        } else {
+            return Ok(None);
+        };
+
+        let hours = EMBEDDER_STORAGE_RETENTION_HOURS
+            .get()
+            .clone()
+            .unwrap_or(DEFAULT_RETENTION_HOURS);
+
+        Ok(Some(Self {
+            operator,
+            public_url: EMBEDDER_STORAGE_PUBLIC_URL.get().clone(),
+            retention: Duration::from_secs(hours * 60 * 60),
+        }))
+    }
+
+    /// Uploads a finished file and returns a url discord can embed.
+    pub async fn upload(&self, path: &Path) -> Result<Uploaded> {
+        let key = object_key(path);
+        let mut writer = self
+            .operator
+            .writer_with(&key)
+            .content_type(content_type(path))
+            .await?;
+
+        //stream it in chunks, these files can be a few hundred MB
+        let mut file = File::open(path).await?;
+        let mut buffer = vec![0; UPLOAD_CHUNK_SIZE];
+        loop {
+            let read = file.read(&mut buffer).await?;
+            if read == 0 {
+                break;
+            }
+            writer.write(buffer[..read].to_vec()).await?;
+        }
+        writer.close().await?;
+
+        debug!("Uploaded {} to {key}", path.display());
+        let url = self.url_for(&key).await?;
+        Ok(Uploaded { key, url })
+    }
+
+    pub async fn delete(&self, key: &str) -> Result<()> {
+        self.operator.delete(key).await?;
+        Ok(())
+    }
+
+    /// How long the url of a fresh upload keeps working, until the sweeper or the presign expiry gets to it.
+    pub fn link_lifetime(&self) -> Duration {
+        match self.public_url {
+            Some(_) => self.retention,
+            None => self.retention.min(MAX_PRESIGN_EXPIRY),
+        }
+    }
+
+    async fn url_for(&self, key: &str) -> Result<Url> {
+        if let Some(base) = &self.public_url {
+            //join() would drop the last path segment of the base if it has no trailing slash
+            let url = format!("{}/{key}", base.as_str().trim_end_matches('/'));
+            return Ok(Url::parse(&url)?);
+        }
+
+        let presigned = self
+            .operator
+            .presign_read(key, self.retention.min(MAX_PRESIGN_EXPIRY))
+            .await
+            .context("backend cannot presign urls, set EMBEDDER_STORAGE_PUBLIC_URL")?;
+        Ok(Url::parse(&presigned.uri().to_string())?)
+    }
+
+    /// Deletes every uploaded object older than the retention period, returns how many were removed.
+    pub async fn sweep_expired(&self) -> Result<usize> {
+        let now = unix_now();
+        let mut removed = 0;
+
+        for entry in self.operator.list(OBJECT_PREFIX).await? {
+            let Some(uploaded_at) = uploaded_at(entry.name()) else {
+                continue; //not one of ours
+            };
+            if now.saturating_sub(uploaded_at) >= self.retention.as_secs() {
+                self.operator.delete(entry.path()).await?;
+                removed += 1;
+            }
+        }
+
+        Ok(removed)
+    }
+
+    pub fn spawn_retention_task(self: Arc<Self>) {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+            loop {
+                interval.tick().await;
+                match self.sweep_expired().await {
+                    Ok(0) => {}
+                    Ok(removed) => info!("Removed {removed} expired embeds from storage"),
+                    Err(err) => warn!("Failed to sweep expired embeds: {err:#}"),
+                }
+            }
+        });
+    }
+}
+
+async fn validate_storage() -> Result<()> {
+    let Some(storage) = EmbedStorage::from_env()? else {
+        info!("No embed storage configured, oversized embeds will be sent as links");
+        return Ok(());
+    };
+
+    storage
+        .operator
+        .check()
+        .await
+        .context("failed to reach embed storage")?;
+
+    if storage.public_url.is_none() && !storage.operator.info().full_capability().presign_read {
+        bail!("embed storage backend cannot presign urls, EMBEDDER_STORAGE_PUBLIC_URL must be set");
+    }
+    Ok(())
+}
+
+//keys look like embeds/<unix secs>-<uuid>.<ext>, the timestamp lets the sweeper skip a stat per object
+fn object_key(path: &Path) -> String {
+    let ext = path
+        .extension()
+        .map(|ext| ext.to_string_lossy().to_string())
+        .unwrap_or_else(|| "bin".to_string());
+    format!("{OBJECT_PREFIX}{}-{}.{ext}", unix_now(), Uuid::new_v4())
+}
+
+fn uploaded_at(name: &str) -> Option<u64> {
+    name.split_once('-')?.0.parse().ok()
+}
+
+fn content_type(path: &Path) -> &'static str {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("mp4") => "video/mp4",
+        Some("webm") => "video/webm",
+        Some("ogg") => "audio/ogg",
+        Some("m4a") => "audio/mp4",
+        Some("mp3") => "audio/mpeg",
+        _ => "application/octet-stream",
+    }
+}
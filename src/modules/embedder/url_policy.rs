@@ -0,0 +1,484 @@
+//! Decides which links the embedder is allowed to hand to yt-dlp.
+//! yt-dlp fetches whatever it's given from our host, so without this anyone could point it at
+//! loopback services, the cloud metadata endpoint or anything else on the private network.
+//!
+//! Links are checked before they're queued, then again once yt-dlp reports where the page and its
+//! formats actually live, which catches redirects and extractors that hand off to another host.
+use crate::prelude::*;
+use futures::future::BoxFuture;
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+};
+use thiserror::Error;
+use url::Host;
+
+register_env!(EMBEDDER_ALLOWED_HOSTS, Option<String>);
+register_env!(EMBEDDER_DENIED_HOSTS, Option<String>);
+
+/// Why a link was refused, the display text is shown to the user as is.
+#[derive(Clone, Debug, Error, PartialEq, Eq)]
+pub enum PolicyError {
+    #[error("only http and https links can be embedded, not {0}")]
+    Scheme(String),
+    #[error("that link doesn't have a host")]
+    MissingHost,
+    #[error("{0} is blocked from being embedded")]
+    Denied(String),
+    #[error("{0} isn't on the list of hosts that can be embedded")]
+    NotAllowed(String),
+    #[error("{0} points at a private or reserved address")]
+    PrivateAddress(String),
+    #[error("{0} couldn't be resolved")]
+    Unresolvable(String),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, poise::ChoiceParameter)]
+pub enum HostRule {
+    #[name = "allow"]
+    Allow,
+    #[name = "deny"]
+    Deny,
+}
+
+impl HostRule {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Allow => "allow",
+            Self::Deny => "deny",
+        }
+    }
+}
+
+impl FromStr for HostRule {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "allow" => Ok(Self::Allow),
+            "deny" => Ok(Self::Deny),
+            other => bail!("unknown host rule {other}"),
+        }
+    }
+}
+
+/// Allow and deny lists of hosts, each entry also covers its subdomains.
+/// An empty allow list allows every host that isn't denied.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct HostRules {
+    pub allow: Vec<String>,
+    pub deny: Vec<String>,
+}
+
+impl HostRules {
+    /// The bot wide lists set by whoever hosts the bot.
+    pub fn from_env() -> Self {
+        Self {
+            allow: parse_host_list(EMBEDDER_ALLOWED_HOSTS.get().as_deref()),
+            deny: parse_host_list(EMBEDDER_DENIED_HOSTS.get().as_deref()),
+        }
+    }
+
+    pub fn push(&mut self, host: String, rule: HostRule) {
+        match rule {
+            HostRule::Allow => self.allow.push(host),
+            HostRule::Deny => self.deny.push(host),
+        }
+    }
+
+    fn check(&self, host: &str) -> Result<(), PolicyError> {
+        if self.deny.iter().any(|denied| host_matches(host, denied)) {
+            return Err(PolicyError::Denied(host.to_string()));
+        }
+        let allowed = self.allow.iter().any(|allowed| host_matches(host, allowed));
+        if !self.allow.is_empty() && !allowed {
+            return Err(PolicyError::NotAllowed(host.to_string()));
+        }
+        Ok(())
+    }
+}
+
+/// Looks up the addresses behind a hostname, swapped out for a fixed table when testing the policy.
+pub trait Resolver: Send + Sync {
+    fn lookup<'a>(&'a self, host: &'a str) -> BoxFuture<'a, io::Result<Vec<IpAddr>>>;
+}
+
+/// Resolves through the system, the same way yt-dlp will.
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    fn lookup<'a>(&'a self, host: &'a str) -> BoxFuture<'a, io::Result<Vec<IpAddr>>> {
+        Box::pin(async move {
+            let addresses = tokio::net::lookup_host((host, 0)).await?;
+            Ok(addresses.map(|address| address.ip()).collect())
+        })
+    }
+}
+
+/// The rules a single job is checked against, the bot wide lists plus the guild's own.
+#[derive(Clone)]
+pub struct UrlPolicy {
+    resolver: Arc<dyn Resolver>,
+    global: HostRules,
+    guild: HostRules,
+}
+
+impl UrlPolicy {
+    pub fn new(resolver: Arc<dyn Resolver>, global: HostRules, guild: HostRules) -> Self {
+        Self {
+            resolver,
+            global,
+            guild,
+        }
+    }
+
+    /// Policy for a job using the system resolver and the env lists.
+    pub fn with_guild_rules(guild: HostRules) -> Self {
+        Self::new(Arc::new(SystemResolver), HostRules::from_env(), guild)
+    }
+
+    /// The guild's own rules, sorted so guilds with the same rules compare equal.
+    /// The bot wide lists are the same for every policy so they're left out.
+    pub fn guild_rules(&self) -> HostRules {
+        let mut rules = self.guild.clone();
+        for list in [&mut rules.allow, &mut rules.deny] {
+            list.sort();
+            list.dedup();
+        }
+        rules
+    }
+
+    /// Checks a link the user gave us.
+    pub async fn check(&self, url: &Url) -> Result<(), PolicyError> {
+        match url.scheme() {
+            "http" | "https" => {}
+            other => return Err(PolicyError::Scheme(other.to_string())),
+        }
+        self.check_host(url).await
+    }
+
+    /// Checks where a link points without caring about the scheme, for media urls yt-dlp found
+    /// which are sometimes streamed over other protocols.
+    pub async fn check_host(&self, url: &Url) -> Result<(), PolicyError> {
+        let host = url.host().ok_or(PolicyError::MissingHost)?;
+        let name = host.to_string().to_lowercase();
+
+        //deny lists win over allow lists, a guild can only narrow what the bot allows
+        self.global.check(&name)?;
+        self.guild.check(&name)?;
+
+        let addresses = match host {
+            Host::Ipv4(ip) => vec![IpAddr::V4(ip)],
+            Host::Ipv6(ip) => vec![IpAddr::V6(ip)],
+            Host::Domain(domain) => self
+                .resolver
+                .lookup(domain)
+                .await
+                .map_err(|_| PolicyError::Unresolvable(name.clone()))?,
+        };
+
+        if addresses.is_empty() {
+            return Err(PolicyError::Unresolvable(name));
+        }
+        //best effort, yt-dlp resolves the host again itself so a rebinding dns server can still answer it
+        //differently, the re-check of what yt-dlp reports narrows that but doesn't close it
+        if !addresses.into_iter().all(is_public) {
+            return Err(PolicyError::PrivateAddress(name));
+        }
+        Ok(())
+    }
+}
+
+/// Whether `host` is `pattern` or one of its subdomains.
+pub fn host_matches(host: &str, pattern: &str) -> bool {
+    host == pattern
+        || host
+            .strip_suffix(pattern)
+            .is_some_and(|prefix| prefix.ends_with('.'))
+}
+
+/// Lowercases and trims a host typed by a user, accepting a full link as well.
+pub fn normalize_host(input: &str) -> Option<String> {
+    let input = input.trim();
+    let host = if input.contains("://") {
+        Url::parse(input).ok()?.host_str()?.to_string()
+    } else {
+        input.trim_start_matches("*.").to_string()
+    };
+    let host = host.trim_matches('.').to_lowercase();
+
+    let valid = !host.is_empty()
+        && host
+            .chars()
+            .all(|c| c.is_alphanumeric() || matches!(c, '.' | '-' | ':' | '[' | ']'));
+    valid.then_some(host)
+}
+
+fn parse_host_list(list: Option<&str>) -> Vec<String> {
+    list.unwrap_or_default()
+        .split(',')
+        .filter_map(normalize_host)
+        .collect()
+}
+
+/// False for loopback, private, link-local (which includes cloud metadata) and other reserved ranges.
+pub fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => is_public_v6(ip),
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, c, _] = ip.octets();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_multicast()
+        || a == 0 //"this network"
+        || (a == 100 && (64..128).contains(&b)) //carrier grade nat
+        || (a == 192 && b == 0 && c == 0) //ietf protocol assignments
+        || (a == 198 && (b == 18 || b == 19)) //benchmarking
+        || a >= 240) //reserved
+}
+
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+    //addresses that carry an ipv4 address are judged by that address
+    if let Some(v4) = ip.to_ipv4_mapped() {
+        return is_public_v4(v4);
+    }
+    let segments = ip.segments();
+    if segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0] {
+        let [.., high, low] = segments;
+        let [a, b] = high.to_be_bytes();
+        let [c, d] = low.to_be_bytes();
+        return is_public_v4(Ipv4Addr::new(a, b, c, d)); //nat64
+    }
+    if segments[0] == 0x2002 {
+        let [a, b] = segments[1].to_be_bytes();
+        let [c, d] = segments[2].to_be_bytes();
+        return is_public_v4(Ipv4Addr::new(a, b, c, d)); //6to4
+    }
+
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_multicast()
+        || (segments[0] & 0xfe00) == 0xfc00 //unique local
+        || (segments[0] & 0xffc0) == 0xfe80 //link local
+        || (segments[0] == 0x2001 && segments[1] == 0x0db8)) //documentation
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    /// Answers from a fixed table, anything missing fails to resolve.
+    /// Ip literals resolve to themselves like they do through the system, urls with schemes other than
+    /// http(s) keep them as a domain.
+    struct FixedResolver(HashMap<&'static str, Vec<IpAddr>>);
+
+    impl Resolver for FixedResolver {
+        fn lookup<'a>(&'a self, host: &'a str) -> BoxFuture<'a, io::Result<Vec<IpAddr>>> {
+            Box::pin(async move {
+                if let Ok(ip) = host.parse() {
+                    return Ok(vec![ip]);
+                }
+                self.0
+                    .get(host)
+                    .cloned()
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "not in the table"))
+            })
+        }
+    }
+
+    fn rules(allow: &[&str], deny: &[&str]) -> HostRules {
+        HostRules {
+            allow: allow.iter().map(ToString::to_string).collect(),
+            deny: deny.iter().map(ToString::to_string).collect(),
+        }
+    }
+
+    fn policy(global: HostRules, guild: HostRules) -> UrlPolicy {
+        let table = HashMap::from([
+            ("example.com", vec!["93.184.216.34".parse().unwrap()]),
+            ("media.example.com", vec!["93.184.216.35".parse().unwrap()]),
+            ("video.test", vec!["93.184.216.36".parse().unwrap()]),
+            ("internal.test", vec!["10.0.0.5".parse().unwrap()]),
+            (
+                "mixed.test",
+                vec![
+                    "93.184.216.34".parse().unwrap(),
+                    "192.168.1.1".parse().unwrap(),
+                ],
+            ),
+            ("empty.test", vec![]),
+        ]);
+        UrlPolicy::new(Arc::new(FixedResolver(table)), global, guild)
+    }
+
+    async fn check(policy: &UrlPolicy, link: &str) -> Result<(), PolicyError> {
+        policy.check(&Url::parse(link).unwrap()).await
+    }
+
+    async fn check_host(policy: &UrlPolicy, link: &str) -> Result<(), PolicyError> {
+        policy.check_host(&Url::parse(link).unwrap()).await
+    }
+
+    #[tokio::test]
+    async fn rejects_other_schemes() {
+        let policy = policy(HostRules::default(), HostRules::default());
+        for link in [
+            "ftp://example.com/a",
+            "file:///etc/passwd",
+            "gopher://example.com",
+        ] {
+            assert!(
+                matches!(check(&policy, link).await, Err(PolicyError::Scheme(_))),
+                "{link}"
+            );
+        }
+        assert_eq!(check(&policy, "https://example.com/watch").await, Ok(()));
+        assert_eq!(check(&policy, "http://example.com/watch").await, Ok(()));
+    }
+
+    #[tokio::test]
+    async fn rejects_private_addresses() {
+        let policy = policy(HostRules::default(), HostRules::default());
+        for link in [
+            "http://127.0.0.1/",
+            "http://127.255.0.9/",
+            "http://10.1.2.3/",
+            "http://172.16.0.1/",
+            "http://172.31.255.255/",
+            "http://192.168.0.10/",
+            "http://169.254.169.254/latest/meta-data/",
+            "http://[::1]/",
+            "http://[::ffff:127.0.0.1]/",
+            "http://[64:ff9b::10.0.0.1]/",
+            "http://[64:ff9b::c0a8:1]/",
+            "http://[2002:7f00:1::]/",
+            "http://[2002:a00:1::1]/",
+            "http://internal.test/",
+        ] {
+            assert!(
+                matches!(
+                    check(&policy, link).await,
+                    Err(PolicyError::PrivateAddress(_))
+                ),
+                "{link}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn allows_public_addresses() {
+        let policy = policy(HostRules::default(), HostRules::default());
+        for link in [
+            "http://93.184.216.34/",
+            "http://172.32.0.1/",
+            "http://[2606:4700::1111]/",
+            "http://[64:ff9b::5db8:d822]/",
+            "http://[2002:5db8:d822::1]/",
+            "https://example.com/",
+        ] {
+            assert_eq!(check(&policy, link).await, Ok(()), "{link}");
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_domains_with_any_private_address() {
+        let policy = policy(HostRules::default(), HostRules::default());
+        assert_eq!(
+            check(&policy, "https://mixed.test/").await,
+            Err(PolicyError::PrivateAddress("mixed.test".to_string()))
+        );
+        assert_eq!(
+            check(&policy, "https://empty.test/").await,
+            Err(PolicyError::Unresolvable("empty.test".to_string()))
+        );
+        assert_eq!(
+            check(&policy, "https://missing.test/").await,
+            Err(PolicyError::Unresolvable("missing.test".to_string()))
+        );
+    }
+
+    #[test]
+    fn host_matches_subdomains_only() {
+        assert!(host_matches("example.com", "example.com"));
+        assert!(host_matches("media.example.com", "example.com"));
+        assert!(host_matches("a.b.example.com", "example.com"));
+        assert!(!host_matches("evil-example.com", "example.com"));
+        assert!(!host_matches("example.com.evil.test", "example.com"));
+        assert!(!host_matches("example.com", "media.example.com"));
+    }
+
+    #[tokio::test]
+    async fn deny_wins_over_allow() {
+        let policy = policy(
+            rules(&["example.com"], &["media.example.com"]),
+            HostRules::default(),
+        );
+        assert_eq!(check(&policy, "https://example.com/").await, Ok(()));
+        assert_eq!(
+            check(&policy, "https://media.example.com/").await,
+            Err(PolicyError::Denied("media.example.com".to_string()))
+        );
+        assert_eq!(
+            check(&policy, "https://video.test/").await,
+            Err(PolicyError::NotAllowed("video.test".to_string()))
+        );
+    }
+
+    #[tokio::test]
+    async fn guild_rules_narrow_env_rules() {
+        let global = rules(&["example.com", "video.test"], &[]);
+
+        let denied = policy(global.clone(), rules(&[], &["video.test"]));
+        assert_eq!(check(&denied, "https://example.com/").await, Ok(()));
+        assert_eq!(
+            check(&denied, "https://video.test/").await,
+            Err(PolicyError::Denied("video.test".to_string()))
+        );
+
+        let allowed = policy(global.clone(), rules(&["example.com"], &[]));
+        assert_eq!(check(&allowed, "https://media.example.com/").await, Ok(()));
+        assert_eq!(
+            check(&allowed, "https://video.test/").await,
+            Err(PolicyError::NotAllowed("video.test".to_string()))
+        );
+
+        //a guild can't allow what the env denies
+        let widened = policy(rules(&[], &["video.test"]), rules(&["video.test"], &[]));
+        assert_eq!(
+            check(&widened, "https://video.test/").await,
+            Err(PolicyError::Denied("video.test".to_string()))
+        );
+    }
+
+    #[tokio::test]
+    async fn check_host_rechecks_redirects_and_formats() {
+        let policy = policy(HostRules::default(), rules(&[], &["video.test"]));
+
+        //formats can be streamed over other protocols, only where they live matters
+        assert_eq!(
+            check_host(&policy, "rtmp://media.example.com/live").await,
+            Ok(())
+        );
+        assert_eq!(
+            check_host(&policy, "https://internal.test/video.mp4").await,
+            Err(PolicyError::PrivateAddress("internal.test".to_string()))
+        );
+        assert_eq!(
+            check_host(&policy, "rtmp://169.254.169.254/").await,
+            Err(PolicyError::PrivateAddress("169.254.169.254".to_string()))
+        );
+        assert_eq!(
+            check_host(&policy, "https://video.test/redirected").await,
+            Err(PolicyError::Denied("video.test".to_string()))
+        );
+    }
+}
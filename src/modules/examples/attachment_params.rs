@@ -3,7 +3,7 @@ use crate::prelude::*;
 
 register_commands!(file_details, totalsize);
 
-#[command(prefix_command, slash_command)]
+#[command(prefix_command, track_edits, slash_command)]
 pub async fn file_details(
     ctx: Context<'_>,
     #[description = "File to examine"] file: Attachment,
@@ -18,7 +18,7 @@ pub async fn file_details(
     Ok(())
 }
 
-#[command(prefix_command)]
+#[command(prefix_command, track_edits)]
 pub async fn totalsize(
     ctx: Context<'_>,
     #[description = "Files to evaluate"] files: Vec<Attachment>,
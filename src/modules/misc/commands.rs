@@ -4,7 +4,7 @@ use crate::prelude::*;
 
 register_commands!(source);
 
-#[command(slash_command, prefix_command)]
+#[command(slash_command, prefix_command, track_edits)]
 pub async fn source(ctx: Context<'_>) -> Result<()> {
     ctx.send(
         CreateReply::new()